@@ -0,0 +1,333 @@
+//! The computer opponent
+//!
+//! `Hard` plays perfectly with a minimax search pruned by alpha-beta,
+//! `Medium` and `Easy` mix in heuristic and random moves so a human
+//! has a chance of winning. A full search is only quick with a few cells
+//! left, so on boards bigger than 3x3 every level plays the heuristic
+//! until `MAX_SEARCH` or fewer cells are free.
+
+use common::{Difficulty, GameStatus, Grid, EMPTY};
+use std::time::{SystemTime, UNIX_EPOCH};
+use check_status;
+
+/// The most free cells the minimax search is run on
+const MAX_SEARCH: usize = 9;
+
+/// The four ways a line can run, as (row, col) steps: across, down and
+/// both diagonals
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
+/// Pick a free cell on the board for `me` to play, returns its (row, col),
+/// or None if the board is full.
+pub fn choose_move(grid: &Grid, me: char, difficulty: Difficulty) -> Option<(usize, usize)> {
+    let board = &grid.data;
+    let win = grid.win as usize;
+    let free = free_cells(board);
+    if free.is_empty() {
+        return None;
+    }
+    let mut rng = Rng::new();
+    let search = free.len() <= MAX_SEARCH;
+    let cell = match difficulty {
+        // Mostly random, with the occasional sensible move
+        Difficulty::Easy => {
+            if rng.below(4) == 0 {
+                heuristic_move(board, win, me, &mut rng)
+            } else {
+                free[rng.below(free.len())]
+            }
+        }
+        // Never misses a win or block, and half the time plays perfectly
+        Difficulty::Medium => {
+            if search && rng.below(2) == 0 {
+                best_move(board, win, me)
+            } else {
+                heuristic_move(board, win, me, &mut rng)
+            }
+        }
+        Difficulty::Hard if search => best_move(board, win, me),
+        Difficulty::Hard => heuristic_move(board, win, me, &mut rng),
+    };
+    Some(cell)
+}
+
+/// The piece the opponent of `me` plays with
+fn other(me: char) -> char {
+    if me == 'X' { 'O' } else { 'X' }
+}
+
+/// Every cell not yet taken, as (row, col)
+fn free_cells(board: &[Vec<char>]) -> Vec<(usize, usize)> {
+    let mut free = Vec::new();
+    for (y, row) in board.iter().enumerate() {
+        for (x, &c) in row.iter().enumerate() {
+            if c == EMPTY {
+                free.push((y, x));
+            }
+        }
+    }
+    free
+}
+
+/// Find a cell that would win the game for `piece` if played
+fn winning_cell(board: &[Vec<char>], win: usize, piece: char) -> Option<(usize, usize)> {
+    let mut work = board.to_vec();
+    for (y, x) in free_cells(board) {
+        work[y][x] = piece;
+        let won = match check_status(&work, win) {
+            GameStatus::XWon => piece == 'X',
+            GameStatus::OWon => piece == 'O',
+            _ => false,
+        };
+        work[y][x] = EMPTY;
+        if won {
+            return Some((y, x));
+        }
+    }
+    None
+}
+
+/// Win if possible, else block, else play the cell on the most lines that
+/// can still be won (see `line_score`), picking at random between equals.
+/// On 3x3 this takes the centre, then a corner.
+fn heuristic_move(board: &[Vec<char>], win: usize, me: char, rng: &mut Rng) -> (usize, usize) {
+    if let Some(cell) = winning_cell(board, win, me) {
+        return cell;
+    }
+    if let Some(cell) = winning_cell(board, win, other(me)) {
+        return cell;
+    }
+    let free = free_cells(board);
+    let scores: Vec<usize> = free.iter().map(|&(y, x)| line_score(board, win, me, y, x)).collect();
+    let top = scores.iter().cloned().max().unwrap_or(0);
+    let best: Vec<(usize, usize)> = free.iter()
+        .zip(scores.iter())
+        .filter(|&(_, &score)| score == top)
+        .map(|(&cell, _)| cell)
+        .collect();
+    best[rng.below(best.len())]
+}
+
+/// How good the free cell (y, x) is for `me`, from every run of `win`
+/// cells through it. A run holding pieces of only one side (or none) can
+/// still be won by that side, and counts more the more pieces it has, so
+/// the computer builds its own lines and gets in the way of the opponent's
+/// before they are one move from winning.
+fn line_score(board: &[Vec<char>], win: usize, me: char, y: usize, x: usize) -> usize {
+    let height = board.len() as isize;
+    let width = board[0].len() as isize;
+    let mut score = 0;
+    for &(dy, dx) in DIRECTIONS.iter() {
+        // Every run of `win` cells along this direction that covers (y, x)
+        for back in 0..win as isize {
+            let start = (y as isize - back * dy, x as isize - back * dx);
+            let end = (start.0 + (win as isize - 1) * dy, start.1 + (win as isize - 1) * dx);
+            if start.0 < 0 || start.1 < 0 || start.1 >= width ||
+               end.0 >= height || end.1 < 0 || end.1 >= width {
+                continue;
+            }
+            let (mut mine, mut theirs) = (0, 0);
+            for i in 0..win as isize {
+                let c = board[(start.0 + i * dy) as usize][(start.1 + i * dx) as usize];
+                if c == me {
+                    mine += 1;
+                } else if c != EMPTY {
+                    theirs += 1;
+                }
+            }
+            if theirs == 0 || mine == 0 {
+                score += 1 + mine * mine + theirs * theirs;
+            }
+        }
+    }
+    score
+}
+
+/// The move with the highest minimax score for `me`
+fn best_move(board: &[Vec<char>], win: usize, me: char) -> (usize, usize) {
+    let mut work = board.to_vec();
+    let free = free_cells(board);
+    let mut best = free[0];
+    let mut best_score = i32::MIN;
+    for (y, x) in free {
+        work[y][x] = me;
+        let score = -minimax(&mut work, win, other(me), 1, -i32::MAX, i32::MAX);
+        work[y][x] = EMPTY;
+        if score > best_score {
+            best_score = score;
+            best = (y, x);
+        }
+    }
+    best
+}
+
+/// Negamax form of minimax with alpha-beta pruning. Scores are from the
+/// point of view of `turn`, the player about to move, and a quicker win
+/// (or slower loss) scores higher.
+fn minimax(board: &mut Vec<Vec<char>>, win: usize, turn: char, depth: i32, mut alpha: i32, beta: i32) -> i32 {
+    match check_status(board, win) {
+        GameStatus::Waiting | GameStatus::InProgress => {}
+        GameStatus::Draw => return 0,
+        // The previous player just won, which is a loss for `turn`. The
+        // search never goes deeper than MAX_SEARCH, so this is below 0.
+        _ => return depth - MAX_SEARCH as i32 - 1,
+    }
+    for (y, x) in free_cells(board) {
+        board[y][x] = turn;
+        let score = -minimax(board, win, other(turn), depth + 1, -beta, -alpha);
+        board[y][x] = EMPTY;
+        if score > alpha {
+            alpha = score;
+        }
+        if alpha >= beta {
+            break;
+        }
+    }
+    alpha
+}
+
+/// A small xorshift generator, good enough for picking moves without
+/// pulling in another crate.
+struct Rng {
+    state: u64,
+}
+impl Rng {
+    fn new() -> Rng {
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() ^ (d.subsec_nanos() as u64) << 32,
+            Err(_) => 0,
+        };
+        // xorshift must never be seeded with zero
+        Rng { state: seed | 1 }
+    }
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+    /// A number in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A board from rows of 'X', 'O' and '.' for an empty cell
+    fn board(rows: &[&str]) -> Vec<Vec<char>> {
+        rows.iter().map(|row| row.chars().map(|c| if c == '.' { EMPTY } else { c }).collect()).collect()
+    }
+
+    fn grid(rows: &[&str], win: u32) -> Grid {
+        let data = board(rows);
+        let status = check_status(&data, win as usize);
+        Grid { data, status, win }
+    }
+
+    #[test]
+    fn status_on_non_square_boards() {
+        // 5 wide, 3 high, 3 in a row wins
+        assert_eq!(check_status(&board(&["..XXX", "OO...", "....."]), 3), GameStatus::XWon);
+        assert_eq!(check_status(&board(&["XX.XX", "OO.OO", "....."]), 3), GameStatus::InProgress);
+        assert_eq!(check_status(&board(&["....O", "X...O", "X...O"]), 3), GameStatus::OWon);
+        // 3 wide, 5 high, a column of 4 is needed
+        assert_eq!(check_status(&board(&["X..", "X..", "X..", "OO.", "..."]), 4), GameStatus::InProgress);
+        assert_eq!(check_status(&board(&["X..", "X..", "X..", "XO.", "OO."]), 4), GameStatus::XWon);
+    }
+
+    #[test]
+    fn status_on_both_diagonals() {
+        let down_right = ["......", ".X....", "..X...", "...X..", "OO.O.O"];
+        assert_eq!(check_status(&board(&down_right), 3), GameStatus::XWon);
+        let down_left = ["......", "....O.", "...O..", "..O...", "XX.X.X"];
+        assert_eq!(check_status(&board(&down_left), 3), GameStatus::OWon);
+        // Lines that would run off the edge don't wrap
+        let edges = ["....X", "X....", ".X...", "....."];
+        assert_eq!(check_status(&board(&edges), 3), GameStatus::InProgress);
+        let corner = [".....", ".....", "....X", "...X.", "..X.."];
+        assert_eq!(check_status(&board(&corner), 3), GameStatus::XWon);
+    }
+
+    #[test]
+    fn status_draw() {
+        assert_eq!(check_status(&board(&["XOXO", "XOXO", "OXOX"]), 3), GameStatus::Draw);
+        assert_eq!(check_status(&board(&["XOXO", "XOXO", "OXO."]), 3), GameStatus::InProgress);
+    }
+
+    #[test]
+    fn moves_are_free_cells() {
+        let game = grid(&["X.O....", "..O.X..", ".......", "O....X."], 4);
+        for &difficulty in &[Difficulty::Easy, Difficulty::Medium, Difficulty::Hard] {
+            for _ in 0..20 {
+                let (y, x) = choose_move(&game, 'O', difficulty).unwrap();
+                assert!(y < 4 && x < 7);
+                assert_eq!(game.data[y][x], EMPTY);
+            }
+        }
+        assert_eq!(choose_move(&grid(&["XOXO", "XOXO", "OXOX"], 3), 'O', Difficulty::Hard), None);
+    }
+
+    #[test]
+    fn wins_and_blocks_when_win_is_less_than_width() {
+        // Too many free cells to search, so the heuristic plays
+        let win_now = grid(&["OO.....", "X.X....", "...X...", "......."], 3);
+        for &difficulty in &[Difficulty::Medium, Difficulty::Hard] {
+            assert_eq!(choose_move(&win_now, 'O', difficulty), Some((0, 2)));
+        }
+        let block = grid(&["XX.....", "O......", "...O...", "......."], 3);
+        assert_eq!(choose_move(&block, 'O', Difficulty::Hard), Some((0, 2)));
+    }
+
+    #[test]
+    fn blocks_both_diagonals() {
+        let down_right = grid(&[".....", ".X...", ".....", "...X.", "O...O"], 4);
+        assert_eq!(choose_move(&down_right, 'O', Difficulty::Hard), Some((2, 2)));
+        let down_left = grid(&[".....", "...X.", ".....", ".X...", "O...O"], 4);
+        assert_eq!(choose_move(&down_left, 'O', Difficulty::Hard), Some((2, 2)));
+        // Few enough cells free to search, on a board wider than it is high
+        let search = grid(&["X.OX", "OX.O", "XO.."], 3);
+        assert_eq!(choose_move(&search, 'O', Difficulty::Hard), Some((2, 2)));
+    }
+
+    /// Play out every game from `data` where `me` moves with `Hard` and the
+    /// other side tries each free cell, returning how many games ended and
+    /// checking `me` never lost one
+    fn never_loses(data: &mut Vec<Vec<char>>, me: char, turn: char) -> usize {
+        let lost = if me == 'X' { GameStatus::OWon } else { GameStatus::XWon };
+        match check_status(data, 3) {
+            GameStatus::InProgress => {}
+            status => {
+                assert!(status != lost, "Hard lost as {} with {:?}", me, data);
+                return 1;
+            }
+        }
+        if turn == me {
+            let game = Grid { data: data.clone(), status: GameStatus::InProgress, win: 3 };
+            let (y, x) = choose_move(&game, me, Difficulty::Hard).unwrap();
+            data[y][x] = me;
+            let games = never_loses(data, me, other(me));
+            data[y][x] = EMPTY;
+            return games;
+        }
+        let mut games = 0;
+        for (y, x) in free_cells(data) {
+            data[y][x] = turn;
+            games += never_loses(data, me, me);
+            data[y][x] = EMPTY;
+        }
+        games
+    }
+
+    #[test]
+    fn hard_never_loses_classic() {
+        // Replying to every first move, and every move after it
+        let played_o = never_loses(&mut board(&["...", "...", "..."]), 'O', 'X');
+        assert!(played_o >= 9);
+        // And going first
+        let played_x = never_loses(&mut board(&["...", "...", "..."]), 'X', 'X');
+        assert!(played_x >= 8);
+    }
+}
@@ -0,0 +1,638 @@
+//! The JSON API, mounted under /api
+//!
+//! ```text
+//! POST   /api/games            start a game, body {"difficulty": "easy",
+//!                              "opponent": "human", "width": 15,
+//!                              "height": 15, "win": 5} (all optional, the
+//!                              board is 3x3 with 3 in a row by default)
+//!                              201 with the game and a Location header
+//! GET    /api/games/:id        200 with the game
+//! POST   /api/games/:id/join   take the second seat of a game against a
+//!                              human, 200 with the game
+//! POST   /api/games/:id/moves  play a cell, body {"cell": 4} or {"row": 1,
+//!                              "col": 1}, 200 with the game
+//!                              after the computer (if playing) has replied
+//! GET    /api/games/:id/moves  200 with every move played so far, in order
+//! POST   /api/games/:id/undo   take back your last move and the computer's
+//!                              reply, 200 with the game
+//! DELETE /api/games/:id        abandon the game, 204
+//! GET    /api/stats            200 with {"games": 12, "users": 30,
+//!                              "expired": 4, "evicted": 0}, the games held
+//!                              and removed for being idle or over the cap
+//! ```
+//!
+//! A game is sent as
+//! `{"id": 1, "difficulty": "hard", "opponent": "computer", "status": "InProgress",
+//! "seat": "X", "turn": "X", "width": 3, "height": 3, "win": 3,
+//! "board": [["0","1","2"], ..]}`, where `seat` is the piece the requesting
+//! user plays and `turn` is null unless the game is in progress. Free cells
+//! on the board hold their number, counting along each row from 0.
+//!
+//! Games belong to the users in the session cookie, which `POST /api/games`
+//! and `POST /api/games/:id/join` set if there isn't one.
+//!
+//! The moves are sent as `{"id": 1, "moves": [{"piece": "X", "cell": 4,
+//! "by": "you", "time": 1500000000000}, ..]}`, `by` being "you", "opponent"
+//! or "computer" and `time` milliseconds since the Unix epoch.
+//!
+//! Errors are the usual status codes with a body of
+//! `{"error": {"status": 404, "reason": "Not Found", "message": ".."}}`,
+//! including a 404 for a url the API doesn't have and a 405 for a method it
+//! doesn't allow.
+//!
+
+use common::{Request, Response, Router, Status, Cookie, Difficulty, Opponent, GameStatus, BoardSize, Cell};
+use common::protocol::{GameInfo, GameRequest, GameResponse, Move};
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+use std::sync::Arc;
+use {Context, session_user, game_error_response, client_error_response, cell_labels};
+
+/// The routes of the API, to be mounted at "/api"
+pub fn routes(context: Arc<Context>) -> Router {
+    let mut router = Router::new();
+    let ctx = context.clone();
+    router.post("/games", move |request: &Request| create_game(request, &ctx));
+    let ctx = context.clone();
+    router.get("/games/:id", move |request: &Request| get_game(request, &ctx));
+    let ctx = context.clone();
+    router.post("/games/:id/join", move |request: &Request| join_game(request, &ctx));
+    let ctx = context.clone();
+    router.post("/games/:id/moves", move |request: &Request| make_move(request, &ctx));
+    let ctx = context.clone();
+    router.get("/games/:id/moves", move |request: &Request| get_moves(request, &ctx));
+    let ctx = context.clone();
+    router.post("/games/:id/undo", move |request: &Request| undo_move(request, &ctx));
+    let ctx = context.clone();
+    router.delete("/games/:id", move |request: &Request| abandon_game(request, &ctx));
+    let ctx = context.clone();
+    router.get("/stats", move |_: &Request| get_stats(&ctx));
+    // Urls and methods the API doesn't have are answered in JSON too
+    router.errors("/", json_error);
+    router
+}
+
+/// Body of `POST /api/games`
+#[derive(RustcDecodable)]
+struct NewGameBody {
+    difficulty: Option<String>,
+    opponent  : Option<String>,
+    width     : Option<u32>,
+    height    : Option<u32>,
+    win       : Option<u32>,
+}
+
+/// Body of `POST /api/games/:id/moves`, either the cell's number or its
+/// row and column
+#[derive(RustcDecodable)]
+struct MoveBody {
+    cell: Option<u32>,
+    row : Option<u32>,
+    col : Option<u32>,
+}
+
+/// A game as the API sends it
+#[derive(RustcEncodable)]
+struct ApiGame {
+    id        : u32,
+    difficulty: String,
+    opponent  : String,
+    status    : GameStatus,
+    seat      : Option<char>,
+    turn      : Option<char>,
+    width     : u32,
+    height    : u32,
+    win       : u32,
+    board     : Vec<Vec<String>>,
+}
+impl ApiGame {
+    /// The game as `user_id` sees it
+    fn from_info(info: GameInfo, user_id: u32) -> ApiGame {
+        ApiGame {
+            id        : info.id,
+            difficulty: info.difficulty.as_str().to_string(),
+            opponent  : info.opponent.as_str().to_string(),
+            status    : info.grid.status,
+            seat      : info.seat(user_id),
+            turn      : info.turn,
+            width     : info.grid.width(),
+            height    : info.grid.height(),
+            win       : info.grid.win,
+            board     : cell_labels(&info.grid.data),
+        }
+    }
+}
+
+/// The moves of a game as the API sends them
+#[derive(RustcEncodable)]
+struct ApiMoves {
+    id   : u32,
+    moves: Vec<ApiMove>,
+}
+
+#[derive(RustcEncodable)]
+struct ApiMove {
+    piece: char,
+    cell : u32,
+    /// "you", "opponent" or "computer"
+    by   : &'static str,
+    time : u64,
+}
+impl ApiMove {
+    /// The move as `user_id` sees it
+    fn from_move(m: Move, user_id: u32) -> ApiMove {
+        let by = match m.player {
+            Some(id) if id == user_id => "you",
+            Some(_) => "opponent",
+            None => "computer",
+        };
+        ApiMove { piece: m.piece, cell: m.cell, by, time: m.time }
+    }
+}
+
+fn create_game(request: &Request, ctx: &Context) -> Response {
+    let body = if request.body.is_some() {
+        match request.decode_body::<NewGameBody>() {
+            Ok(body) => body,
+            Err(e) => return json_error(e),
+        }
+    } else {
+        NewGameBody { difficulty: None, opponent: None, width: None, height: None, win: None }
+    };
+    let difficulty = match body.difficulty {
+        Some(ref d) => Difficulty::from_name(&d.to_lowercase()),
+        None => Difficulty::Hard,
+    };
+    let opponent = match body.opponent {
+        Some(ref o) => Opponent::from_name(&o.to_lowercase()),
+        None => Opponent::Computer,
+    };
+    let classic = BoardSize::classic();
+    let size = BoardSize {
+        width : body.width.unwrap_or(classic.width),
+        height: body.height.unwrap_or(classic.height),
+        win   : body.win.unwrap_or(classic.win),
+    };
+    // Like the html pages, a visitor without a session is given one
+    let (user_id, cookie) = match session_user(request, ctx) {
+        Ok(o) => o,
+        Err(e) => return json_error(e),
+    };
+
+    let request = GameRequest::NewGame { user_id, difficulty, opponent, size };
+    let info = match call(ctx, request) {
+        Ok(info) => info,
+        Err(e) => return e,
+    };
+    let location = format!("/api/games/{}", info.id);
+    let mut response = game_response(Status::created(), info, user_id);
+    response.header("Location", &location);
+    set_cookie(response, cookie)
+}
+
+/// Add the cookie for a new session to a response
+fn set_cookie(mut response: Response, cookie: Option<Cookie>) -> Response {
+    if let Some(cookie) = cookie {
+        if let Err(e) = response.set_cookie(cookie) {
+            println!("{}", e);
+            return json_error(Status::internal_error());
+        }
+    }
+    response
+}
+
+fn get_game(request: &Request, ctx: &Context) -> Response {
+    let (user_id, game_id) = match user_and_game(request, ctx) {
+        Ok(ids) => ids,
+        Err(e) => return e,
+    };
+    match call(ctx, GameRequest::GetGame { user_id, game_id: Some(game_id) }) {
+        Ok(info) => game_response(Status::ok(), info, user_id),
+        Err(e) => gone(ctx, game_id, user_id, e),
+    }
+}
+
+fn join_game(request: &Request, ctx: &Context) -> Response {
+    let game_id = match request.path_params.get("id").and_then(|id| id.parse::<u32>().ok()) {
+        Some(id) => id,
+        None => return json_error(Status::not_found()),
+    };
+    // The invited player may not have visited before
+    let (user_id, cookie) = match session_user(request, ctx) {
+        Ok(o) => o,
+        Err(e) => return json_error(e),
+    };
+    match call(ctx, GameRequest::JoinGame { user_id, game_id }) {
+        Ok(info) => {
+            ctx.live.publish(&info);
+            set_cookie(game_response(Status::ok(), info, user_id), cookie)
+        }
+        Err(e) => e,
+    }
+}
+
+fn make_move(request: &Request, ctx: &Context) -> Response {
+    let (user_id, game_id) = match user_and_game(request, ctx) {
+        Ok(ids) => ids,
+        Err(e) => return e,
+    };
+    let body = match request.decode_body::<MoveBody>() {
+        Ok(body) => body,
+        Err(e) => return json_error(e),
+    };
+    let cell = match (body.cell, body.row, body.col) {
+        (Some(index), None, None) => Cell::Index(index),
+        (None, Some(row), Some(col)) => Cell::At(row, col),
+        _ => return json_error(Status::faulty_query("Send either \"cell\", or \"row\" and \"col\"")),
+    };
+    let request = GameRequest::MakeMove { user_id, game_id: Some(game_id), cell };
+    match call(ctx, request) {
+        Ok(info) => {
+            // The other player's page is told about the move
+            ctx.live.publish(&info);
+            game_response(Status::ok(), info, user_id)
+        }
+        Err(e) => gone(ctx, game_id, user_id, e),
+    }
+}
+
+fn get_moves(request: &Request, ctx: &Context) -> Response {
+    let (user_id, game_id) = match user_and_game(request, ctx) {
+        Ok(ids) => ids,
+        Err(e) => return e,
+    };
+    let request = GameRequest::GetMoves { user_id, game_id: Some(game_id) };
+    let moves = match ctx.client.call(&[request]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::Moves(id, moves)) => ApiMoves {
+                id,
+                moves: moves.into_iter().map(|m| ApiMove::from_move(m, user_id)).collect(),
+            },
+            Some(GameResponse::Error(e)) => return json_error(game_error_response(&e)),
+            _ => return json_error(Status::internal_error()),
+        },
+        Err(e) => return json_error(client_error_response(e)),
+    };
+    json_response(&moves)
+}
+
+fn undo_move(request: &Request, ctx: &Context) -> Response {
+    let (user_id, game_id) = match user_and_game(request, ctx) {
+        Ok(ids) => ids,
+        Err(e) => return e,
+    };
+    match call(ctx, GameRequest::Undo { user_id, game_id: Some(game_id) }) {
+        Ok(info) => {
+            ctx.live.publish(&info);
+            game_response(Status::ok(), info, user_id)
+        }
+        Err(e) => gone(ctx, game_id, user_id, e),
+    }
+}
+
+fn abandon_game(request: &Request, ctx: &Context) -> Response {
+    let (user_id, game_id) = match user_and_game(request, ctx) {
+        Ok(ids) => ids,
+        Err(e) => return e,
+    };
+    let request = GameRequest::Abandon { user_id, game_id: Some(game_id) };
+    match ctx.client.call(&[request]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::Abandoned(_)) => {
+                ctx.live.close(game_id);
+                Status::no_content()
+            }
+            Some(GameResponse::Error(e)) => json_error(game_error_response(&e)),
+            _ => json_error(Status::internal_error()),
+        },
+        Err(e) => json_error(client_error_response(e)),
+    }
+}
+
+/// The game_server's counts, no session is needed
+fn get_stats(ctx: &Context) -> Response {
+    let stats = match ctx.client.call(&[GameRequest::GetStats]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::Stats(stats)) => stats,
+            Some(GameResponse::Error(e)) => return json_error(game_error_response(&e)),
+            _ => return json_error(Status::internal_error()),
+        },
+        Err(e) => return json_error(client_error_response(e)),
+    };
+    json_response(&stats)
+}
+
+/// The user from the session and the game id from the url. A game id that
+/// isn't a number can't be a game, so is a 404.
+#[allow(clippy::result_large_err)]
+fn user_and_game(request: &Request, ctx: &Context) -> Result<(u32, u32), Response> {
+    let user_id = match ctx.sessions.user_id(request) {
+        Some(id) => id,
+        None => return Err(json_error(message(Status::forbidden(), "No session, start a game first"))),
+    };
+    let game_id = match request.path_params.get("id").and_then(|id| id.parse::<u32>().ok()) {
+        Some(id) => id,
+        None => return Err(json_error(Status::not_found())),
+    };
+    Ok((user_id, game_id))
+}
+
+/// Pass on an error for a request about `game_id`, first closing the
+/// game's live updates if it says the game has been removed
+fn gone(ctx: &Context, game_id: u32, user_id: u32, response: Response) -> Response {
+    ctx.live.check_removed(game_id, user_id, &response);
+    response
+}
+
+/// Send one request to the game_server, expecting a game back
+#[allow(clippy::result_large_err)]
+fn call(ctx: &Context, request: GameRequest) -> Result<GameInfo, Response> {
+    match ctx.client.call(&[request]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::Game(info)) => Ok(info),
+            Some(GameResponse::Error(e)) => Err(json_error(game_error_response(&e))),
+            _ => Err(json_error(Status::internal_error())),
+        },
+        Err(e) => Err(json_error(client_error_response(e))),
+    }
+}
+
+/// `response` with the game, as `user_id` sees it, as its JSON body
+fn game_response(mut response: Response, info: GameInfo, user_id: u32) -> Response {
+    match json::encode(&ApiGame::from_info(info, user_id)) {
+        Ok(body) => {
+            response.header("Content-Type", "application/json");
+            response.body(body.into_bytes());
+            response
+        }
+        Err(_) => json_error(Status::internal_error()),
+    }
+}
+
+/// A 200 with `body` encoded as JSON
+fn json_response<T: Encodable>(body: &T) -> Response {
+    match json::encode(body) {
+        Ok(body) => {
+            let mut response = Status::ok();
+            response.header("Content-Type", "application/json");
+            response.body(body.into_bytes());
+            response
+        }
+        Err(_) => json_error(Status::internal_error()),
+    }
+}
+
+fn message(mut response: Response, text: &str) -> Response {
+    response.body(text.as_bytes().to_vec());
+    response
+}
+
+#[derive(RustcEncodable)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(RustcEncodable)]
+struct ErrorDetail {
+    status : u16,
+    reason : String,
+    message: String,
+}
+
+/// Turn an error Response from the `Status` templates in to a JSON error.
+/// The status and headers (eg Retry-After) are kept, any text body becomes
+/// the message, otherwise the reason phrase is used.
+fn json_error(mut response: Response) -> Response {
+    let reason = response.code.splitn(3, ' ').nth(2).unwrap_or("").to_string();
+    let message = match response.body {
+        Some(ref body) if !body.is_empty() => String::from_utf8_lossy(body).into_owned(),
+        _ => reason.clone(),
+    };
+    let body = ErrorBody {
+        error: ErrorDetail { status: response.status_code(), reason, message },
+    };
+    response.header("Content-Type", "application/json");
+    match json::encode(&body) {
+        Ok(body) => response.body(body.into_bytes()),
+        Err(_) => response.body = None,
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Body, Grid, Templates, EMPTY};
+    use common::protocol::{self, ErrorCode, GameError};
+    use game_client::GameClient;
+    use live::Live;
+    use rustc_serialize::json::Json;
+    use session::{Sessions, COOKIE};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The user the fake game_server gives out, who owns game 1
+    const USER: u32 = 7;
+
+    /// The API in front of a fake game_server, which keeps every request
+    /// it is sent
+    struct Api {
+        router: Router,
+        ctx   : Arc<Context>,
+        sent  : Arc<Mutex<Vec<GameRequest>>>,
+    }
+    impl Api {
+        fn new() -> Api {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let log = sent.clone();
+            thread::spawn(move || for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let log = log.clone();
+                thread::spawn(move || {
+                    while let Ok(Some(request)) = protocol::read_frame::<_, GameRequest>(&mut stream) {
+                        let reply = answer(&request);
+                        log.lock().unwrap().push(request);
+                        protocol::write_frame(&mut stream, &reply).unwrap();
+                    }
+                });
+            });
+            let ctx = Arc::new(Context {
+                client   : GameClient::new(&addr, 2, Duration::from_secs(2)),
+                sessions : Sessions::from_env(),
+                templates: Templates::new(),
+                live     : Live::new(4, 4),
+            });
+            let mut router = Router::new();
+            router.mount("/api", routes(ctx.clone()));
+            Api { router, ctx, sent }
+        }
+        /// Dispatch a request, with a JSON body and the user's session if given
+        fn send(&self, method: &str, url: &str, body: Option<&str>, user: Option<u32>) -> Response {
+            let mut request = Request::new();
+            request.method = method.to_string();
+            request.url = url.to_string();
+            request.version = "HTTP/1.1".to_string();
+            request.body = body.map(|body| Body::Json(Json::from_str(body).unwrap()));
+            if let Some(user) = user {
+                request.cookies.insert(COOKIE.to_string(), self.ctx.sessions.sign(user));
+            }
+            self.router.dispatch(&mut request)
+        }
+        /// The last request the game_server was sent
+        fn last_sent(&self) -> GameRequest {
+            self.sent.lock().unwrap().last().unwrap().clone()
+        }
+    }
+
+    /// The fake game_server, which has only game 1
+    fn answer(request: &GameRequest) -> GameResponse {
+        match *request {
+            GameRequest::NewUser => GameResponse::User(USER),
+            GameRequest::NewGame { user_id, difficulty, opponent, size } => {
+                GameResponse::Game(game(user_id, difficulty, opponent, size))
+            }
+            GameRequest::MakeMove { cell: Cell::Index(9), .. } => {
+                GameResponse::Error(GameError::new(ErrorCode::IllegalMove, "Cell 9 is off the board"))
+            }
+            GameRequest::GetGame { user_id: USER, game_id: Some(1) } |
+            GameRequest::MakeMove { user_id: USER, game_id: Some(1), .. } => {
+                GameResponse::Game(game(USER, Difficulty::Hard, Opponent::Computer, BoardSize::classic()))
+            }
+            GameRequest::Abandon { user_id: USER, game_id: Some(1) } => GameResponse::Abandoned(1),
+            _ => GameResponse::Error(GameError::new(ErrorCode::NoSuchGame, "No such game")),
+        }
+    }
+
+    fn game(owner: u32, difficulty: Difficulty, opponent: Opponent, size: BoardSize) -> GameInfo {
+        let data = vec![vec![EMPTY; size.width as usize]; size.height as usize];
+        GameInfo {
+            id: 1,
+            difficulty,
+            opponent,
+            owner,
+            guest: None,
+            turn: Some('X'),
+            seq: 0,
+            grid: Grid { data, status: GameStatus::InProgress, win: size.win },
+        }
+    }
+
+    fn body(response: &Response) -> Json {
+        assert_eq!(response.headers.get("Content-Type").map(|t| t.as_str()), Some("application/json"));
+        Json::from_str(&String::from_utf8(response.body.clone().unwrap()).unwrap()).unwrap()
+    }
+
+    fn text<'a>(json: &'a Json, path: &[&str]) -> &'a str {
+        json.find_path(path).and_then(|val| val.as_string()).unwrap()
+    }
+
+    /// Check `response` is a JSON error with `status`, returning its message
+    fn error(response: &Response, status: u16) -> String {
+        assert_eq!(response.status_code(), status);
+        let json = body(response);
+        assert_eq!(json.find_path(&["error", "status"]).and_then(|s| s.as_u64()), Some(status as u64));
+        assert!(!text(&json, &["error", "reason"]).is_empty());
+        text(&json, &["error", "message"]).to_string()
+    }
+
+    #[test]
+    fn create_game() {
+        let api = Api::new();
+        let response = api.send("POST", "/api/games",
+                                Some(r#"{"difficulty": "Easy", "opponent": "HUMAN", "width": 4, "height": 5, "win": 3}"#),
+                                None);
+        assert_eq!(response.status_code(), 201);
+        assert_eq!(response.headers.get("Location").map(|l| l.as_str()), Some("/api/games/1"));
+        // A new visitor is given a session
+        let cookie = response.headers.get("Set-Cookie").unwrap();
+        assert!(cookie.starts_with(&format!("{}={}", COOKIE, api.ctx.sessions.sign(USER))));
+        let json = body(&response);
+        assert_eq!(text(&json, &["difficulty"]), "easy");
+        assert_eq!(text(&json, &["opponent"]), "human");
+        assert_eq!(text(&json, &["status"]), "InProgress");
+        assert_eq!(text(&json, &["seat"]), "X");
+        assert_eq!(json.find("width").and_then(|w| w.as_u64()), Some(4));
+        assert_eq!(json.find("board").and_then(|b| b.as_array()).map(|b| b.len()), Some(5));
+        let size = BoardSize { width: 4, height: 5, win: 3 };
+        assert_eq!(*api.sent.lock().unwrap(),
+                   vec![GameRequest::NewUser,
+                        GameRequest::NewGame { user_id: USER, difficulty: Difficulty::Easy,
+                                               opponent: Opponent::Human, size }]);
+
+        // With a session and no body, the defaults
+        let response = api.send("POST", "/api/games", None, Some(USER));
+        assert_eq!(response.status_code(), 201);
+        assert!(!response.headers.contains("Set-Cookie"));
+        let json = body(&response);
+        assert_eq!(text(&json, &["difficulty"]), "hard");
+        assert_eq!(text(&json, &["opponent"]), "computer");
+        assert_eq!(api.last_sent(), GameRequest::NewGame { user_id: USER, difficulty: Difficulty::Hard,
+                                                           opponent: Opponent::Computer,
+                                                           size: BoardSize::classic() });
+    }
+
+    #[test]
+    fn get_game() {
+        let api = Api::new();
+        let response = api.send("GET", "/api/games/1", None, Some(USER));
+        assert_eq!(response.status_code(), 200);
+        let json = body(&response);
+        assert_eq!(json.find("id").and_then(|id| id.as_u64()), Some(1));
+        assert_eq!(text(&json, &["opponent"]), "computer");
+        assert_eq!(text(&json, &["turn"]), "X");
+        assert_eq!(json.find_path(&["board"]).and_then(|b| b.as_array()).map(|b| b[2].clone()),
+                   Some(Json::from_str(r#"["6", "7", "8"]"#).unwrap()));
+
+        assert_eq!(error(&api.send("GET", "/api/games/2", None, Some(USER)), 404), "Not Found");
+        assert_eq!(error(&api.send("GET", "/api/games/abc", None, Some(USER)), 404), "Not Found");
+        // A session is needed, and is never made by looking
+        assert_eq!(error(&api.send("GET", "/api/games/1", None, None), 403), "No session, start a game first");
+        // Only games 1 and 2 were asked for, "abc" can't be a game
+        assert_eq!(api.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn make_move() {
+        let api = Api::new();
+        let response = api.send("POST", "/api/games/1/moves", Some(r#"{"cell": 4}"#), Some(USER));
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(api.last_sent(), GameRequest::MakeMove { user_id: USER, game_id: Some(1), cell: Cell::Index(4) });
+        let response = api.send("POST", "/api/games/1/moves", Some(r#"{"row": 1, "col": 2}"#), Some(USER));
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(api.last_sent(), GameRequest::MakeMove { user_id: USER, game_id: Some(1), cell: Cell::At(1, 2) });
+
+        let both = api.send("POST", "/api/games/1/moves", Some(r#"{"cell": 4, "row": 1}"#), Some(USER));
+        assert_eq!(error(&both, 422), "Send either \"cell\", or \"row\" and \"col\"");
+        assert!(error(&api.send("POST", "/api/games/1/moves", None, Some(USER)), 422).contains("No data"));
+        // The game_server's errors are passed on
+        let off = api.send("POST", "/api/games/1/moves", Some(r#"{"cell": 9}"#), Some(USER));
+        assert_eq!(error(&off, 422), "Cell 9 is off the board");
+        let other = api.send("POST", "/api/games/2/moves", Some(r#"{"cell": 4}"#), Some(USER));
+        assert_eq!(error(&other, 404), "Not Found");
+    }
+
+    #[test]
+    fn abandon_game() {
+        let api = Api::new();
+        let response = api.send("DELETE", "/api/games/1", None, Some(USER));
+        assert_eq!(response.status_code(), 204);
+        assert!(response.body.is_none());
+        assert_eq!(api.last_sent(), GameRequest::Abandon { user_id: USER, game_id: Some(1) });
+        assert_eq!(error(&api.send("DELETE", "/api/games/2", None, Some(USER)), 404), "Not Found");
+        assert_eq!(error(&api.send("DELETE", "/api/games/1", None, None), 403), "No session, start a game first");
+    }
+
+    #[test]
+    fn unknown_urls_and_methods() {
+        let api = Api::new();
+        assert_eq!(error(&api.send("GET", "/api/players", None, Some(USER)), 404), "Not Found");
+        let response = api.send("PUT", "/api/games/1", None, Some(USER));
+        assert_eq!(error(&response, 405), "Method Not Allowed");
+        assert_eq!(response.headers.get("Allow").map(|a| a.as_str()), Some("GET, DELETE, HEAD"));
+        assert!(api.sent.lock().unwrap().is_empty());
+    }
+}
@@ -1,35 +1,303 @@
 extern crate rustc_serialize;
 
-use std::io::Read;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::str;
 use std::collections::HashMap;
 use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // This is a library consisting of all data structs and/or
 // funcitonality shared between the web and game servers
 
-/// Outgoing and incoming data is parsed to this via JSON
+mod cookie;
+pub mod crypto;
+mod headers;
+mod pool;
+pub mod protocol;
+mod router;
+pub mod shutdown;
+mod static_files;
+pub mod template;
+pub mod websocket;
+pub use cookie::{Cookie, SameSite};
+pub use headers::{Headers, HeaderError};
+pub use pool::{ThreadPool, PoolError, Slots, Slot};
+pub use router::{Router, Handler, ErrorHook};
+pub use static_files::StaticFiles;
+pub use template::{Templates, TemplateError};
+
+/// A move (or new game) as sent by the html form or JSON body, the web server
+/// turns this in to `protocol::GameRequest`s for the game server. The user
+/// is taken from the session, not from here.
 #[derive(RustcEncodable, RustcDecodable, Debug)]
 pub struct UserData {
-    pub user_id : u32,
-    pub move_to : char,
-    pub new_game: bool,
+    /// The cell to play, its number or "row,col" (see `Cell::parse`)
+    pub move_to   : String,
+    pub new_game  : bool,
+    pub difficulty: Difficulty,
+    /// The board for a new game, 3x3 with 3 in a row if None
+    pub size      : Option<BoardSize>,
 }
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+
+/// How well the computer plays, chosen when a new game is started
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+impl Difficulty {
+    /// Parse the value sent by the html form, anything unknown is `Hard`
+    pub fn from_name(s: &str) -> Difficulty {
+        match s {
+            "easy" => Difficulty::Easy,
+            "medium" => Difficulty::Medium,
+            _ => Difficulty::Hard,
+        }
+    }
+    /// The value the html form sends for this difficulty
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+        }
+    }
+}
+/// Who plays the second seat (O) of a game
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum Opponent {
+    /// The game_server plays O, replying straight after each move
+    Computer,
+    /// A second user, who joins the game by its id
+    Human,
+}
+impl Opponent {
+    /// Parse the value sent by a form or the API, anything unknown is `Computer`
+    pub fn from_name(s: &str) -> Opponent {
+        match s {
+            "human" => Opponent::Human,
+            _ => Opponent::Computer,
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Opponent::Computer => "computer",
+            Opponent::Human => "human",
+        }
+    }
+}
+
+/// The board and its status as sent from the game server. Each cell holds
+/// 'X', 'O' or `EMPTY`, rows from the top.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
 pub struct Grid {
-    data: Vec<Vec<char>>
+    pub data  : Vec<Vec<char>>,
+    pub status: GameStatus,
+    /// How many pieces in a row (across, down or diagonal) win
+    pub win   : u32,
+}
+impl Grid {
+    pub fn width(&self) -> u32 {
+        self.data.first().map_or(0, |row| row.len() as u32)
+    }
+    pub fn height(&self) -> u32 {
+        self.data.len() as u32
+    }
 }
 
-enum State { Method, Headers, Body }
-enum MState { Method, Url, Http, End }
-enum HState { Key, Space, Value, Ret, Insert, End }
+/// What a free cell of a `Grid` holds
+pub const EMPTY: char = ' ';
+/// The longest side a board may have
+pub const MAX_BOARD_SIDE: u32 = 19;
+
+/// The shape of a board for a new game, an m,n,k game in general, where
+/// `win` pieces in a row on a `width` by `height` board win. Tic tac toe
+/// is 3,3,3 and Gomoku 15,15,5.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub struct BoardSize {
+    pub width : u32,
+    pub height: u32,
+    pub win   : u32,
+}
+impl BoardSize {
+    /// Plain tic tac toe
+    pub fn classic() -> BoardSize {
+        BoardSize { width: 3, height: 3, win: 3 }
+    }
+    /// Check the board can be played on, and the win is possible on it
+    pub fn check(&self) -> Result<(), String> {
+        if self.width < 1 || self.height < 1 || self.width > MAX_BOARD_SIDE || self.height > MAX_BOARD_SIDE {
+            return Err(format!("A board must be 1 to {} cells on each side", MAX_BOARD_SIDE));
+        }
+        if self.win < 1 || self.win > self.width.max(self.height) {
+            return Err(format!("Win length must be 1 to {}", self.width.max(self.height)));
+        }
+        Ok(())
+    }
+}
+
+/// A cell of a board, by its number counting along each row from 0 in the
+/// top left, or by row and column (each from 0)
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum Cell {
+    Index(u32),
+    At(u32, u32),
+}
+impl Cell {
+    /// Parse a cell as typed in to the html form, "7" or "2,1" (row, column)
+    pub fn parse(s: &str) -> Option<Cell> {
+        let mut parts = s.split(',').map(|part| part.trim().parse::<u32>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(index)), None, _) => Some(Cell::Index(index)),
+            (Some(Ok(row)), Some(Ok(col)), None) => Some(Cell::At(row, col)),
+            _ => None,
+        }
+    }
+    /// The (row, column) of the cell on a `width` by `height` board, None
+    /// if it is off the board
+    pub fn position(&self, width: u32, height: u32) -> Option<(usize, usize)> {
+        let (row, col) = match *self {
+            Cell::Index(index) if width > 0 => (index / width, index % width),
+            Cell::Index(_) => return None,
+            Cell::At(row, col) => (row, col),
+        };
+        if row < height && col < width {
+            Some((row as usize, col as usize))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where a game is at, a game is over once it is won or drawn
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum GameStatus {
+    /// A game against a human that no one has joined yet
+    Waiting,
+    InProgress,
+    XWon,
+    OWon,
+    Draw,
+}
+impl GameStatus {
+    pub fn is_over(&self) -> bool {
+        match *self {
+            GameStatus::Waiting | GameStatus::InProgress => false,
+            GameStatus::XWon | GameStatus::OWon | GameStatus::Draw => true,
+        }
+    }
+}
+
+/// Size limits applied while parsing a request, anything over these is
+/// rejected rather than read in to memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Request line plus all headers, in bytes
+    pub max_header_bytes: usize,
+    /// Decoded body, in bytes
+    pub max_body_bytes  : usize,
+}
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::new()
+    }
+}
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_header_bytes: 8 * 1024,
+            max_body_bytes  : 64 * 1024,
+        }
+    }
+}
+
+/// The ways reading a Request can fail. Each maps to the status code
+/// the client should be sent, see `ParseError::response`.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The stream ended before any bytes of a request were sent
+    Closed,
+    /// 400, the request is malformed
+    BadRequest(String),
+    /// 411, a PUT gave no length for its body
+    LengthRequired,
+    /// 413, the body is over `Limits::max_body_bytes`
+    PayloadTooLarge,
+    /// 431, the request line and headers are over `Limits::max_header_bytes`
+    HeadersTooLarge,
+    /// 505, not HTTP/1.0 or HTTP/1.1
+    VersionNotSupported(String),
+    /// The stream itself failed
+    Io(io::Error),
+}
+impl ParseError {
+    /// A prebuilt Response for this error, None if nothing can be sent
+    /// because the connection is gone.
+    pub fn response(&self) -> Option<Response> {
+        let mut res = match *self {
+            ParseError::Closed | ParseError::Io(_) => return None,
+            ParseError::BadRequest(ref msg) => {
+                let mut res = Status::bad_request();
+                res.body(msg.as_bytes().to_vec());
+                res
+            }
+            ParseError::LengthRequired => Status::length_required(),
+            ParseError::PayloadTooLarge => Status::payload_too_large(),
+            ParseError::HeadersTooLarge => Status::header_fields_too_large(),
+            ParseError::VersionNotSupported(_) => Status::version_not_supported(),
+        };
+        res.header("Connection", "close");
+        Some(res)
+    }
+}
 
 /// Incoming streams should be parsed to this struct
 pub struct Request {
     pub method : String,
     pub url    : String,
-    pub headers: HashMap<String, String>,
-    pub body   : Option<HashMap<String, String>>, // TODO make enum so can use a HashMap or Vec
+    pub version: String,
+    pub headers: Headers,
+    /// Params from the query string of the url
+    pub query  : Params,
+    /// Params taken from the url by the `Router`, eg `id` for "/game/:id"
+    pub path_params: HashMap<String, String>,
+    /// Cookies sent in the `Cookie` header, by name
+    pub cookies: HashMap<String, String>,
+    /// The body, decoded according to its `Content-Type`
+    pub body   : Option<Body>,
+}
+
+/// A request body, which variant is picked by the `Content-Type` header
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    /// application/x-www-form-urlencoded
+    Form(Params),
+    /// application/json
+    Json(Json),
+    /// multipart/form-data, one Part per field or file
+    Multipart(Vec<Part>),
+    /// Anything else, left as the bytes sent
+    Raw(Vec<u8>),
+}
+
+/// A single field of a multipart/form-data body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub name        : String,
+    pub filename    : Option<String>,
+    pub content_type: Option<String>,
+    pub data        : Vec<u8>,
+}
+impl Default for Request {
+    fn default() -> Request {
+        Request::new()
+    }
 }
 impl Request {
     /// Produce a blank Request
@@ -37,32 +305,98 @@ impl Request {
         Request {
             method  : String::new(),
             url     : String::new(),
-            headers : HashMap::new(),
+            version : String::new(),
+            headers : Headers::new(),
+            query   : Params::new(),
+            path_params: HashMap::new(),
+            cookies : HashMap::new(),
             body    : None,
         }
     }
-    /// fetch any param in the request body, return either a ref to the
+    /// fetch any param in the request body, falling back to the query
+    /// string if the body doesn't have it. Return either a ref to the
     /// string, or a Response that can be used if desired.
     ///
+    #[allow(clippy::result_large_err)]
     pub fn get_param(&self, param: &str) -> Result<&String, Response> {
+        match self.get_form(param) {
+            Ok(p) => Ok(p),
+            Err(_) => self.get_query(param),
+        }
+    }
+    /// fetch a param from the query string only
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn get_query(&self, param: &str) -> Result<&String, Response> {
+        non_empty(param, self.query.get(param))
+    }
+    /// fetch a cookie sent by the client
+    pub fn get_cookie(&self, name: &str) -> Option<&String> {
+        self.cookies.get(name)
+    }
+    /// fetch a param captured from the url by the `Router`
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn get_path_param(&self, param: &str) -> Result<&String, Response> {
+        non_empty(param, self.path_params.get(param))
+    }
+    /// fetch a param from a form body only
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn get_form(&self, param: &str) -> Result<&String, Response> {
         match self.body {
-            Some(ref map) => {
-                match map.get(param) {
-                    Some(p) => {
-                        if p.is_empty() {
-                            return Err(Status::faulty_query(&format!("{:?} was empty", param)))
-                        }
-                        return Ok(p)
-                    },
-                    None => return Err(Status::faulty_query(&format!("No {:?} submitted in query", param)))
-                };
-            },
-            None => {
-                return Err(Status::faulty_query("No data submitted in query"))
-            },
+            Some(Body::Form(ref form)) => non_empty(param, form.get(param)),
+            _ => Err(Status::faulty_query("No data submitted in query")),
+        }
+    }
+    /// Was the body sent as JSON
+    pub fn is_json(&self) -> bool {
+        matches!(self.body, Some(Body::Json(_)))
+    }
+    /// Decode a JSON or form body in to any decodable type, eg `UserData`.
+    /// A form is decoded as if it were a JSON object of strings, with
+    /// "true" and "false" taken as booleans.
+    ///
+    /// example:
+    ///    let user_data: UserData = match request.decode_body() {
+    ///        Ok(data) => data,
+    ///        Err(response) => return response,
+    ///    };
+    ///
+    #[allow(clippy::result_large_err)]
+    pub fn decode_body<T: Decodable>(&self) -> Result<T, Response> {
+        let value = match self.body {
+            Some(Body::Json(ref value)) => value.clone(),
+            Some(Body::Form(ref form)) => form.to_json(),
+            Some(_) => return Err(Status::unsupported_media_type()),
+            None => return Err(Status::faulty_query("No data submitted in query")),
+        };
+        let mut decoder = json::Decoder::new(value);
+        match T::decode(&mut decoder) {
+            Ok(o) => Ok(o),
+            Err(e) => Err(Status::faulty_query(&format!("Could not decode body: {}", e))),
+        }
+    }
+    /// Fetch a header by name, ignoring the case of the name
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 is persistent unless `Connection: close` is sent, HTTP/1.0
+    /// only if `Connection: keep-alive` is sent.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| match self.get_header("Connection") {
+            Some(val) => val.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+            None => false,
         };
+        if self.version == "HTTP/1.1" {
+            !has_token("close")
+        } else {
+            has_token("keep-alive")
+        }
     }
-    /// Parse any stream of bytes (u8) in to a Request if the stream is valid
+    /// Parse a TcpStream in to a Request if the stream is valid, using the
+    /// default `Limits`
     ///
     /// example:
     ///     let listener = TcpListener::bind("localhost:3000").unwrap();
@@ -70,160 +404,488 @@ impl Request {
     ///         let request = parse_stream(&mut stream).unwrap();
     ///     }
     ///
-    pub fn parse_stream(stream: &mut TcpStream) -> Result<Request, &str> {
+    pub fn parse_stream(stream: &mut TcpStream) -> Result<Request, ParseError> {
+        let mut reader = BufReader::new(stream);
+        Request::read_from(&mut reader, &Limits::new())
+    }
+    /// Read exactly one request from a buffered reader. Nothing past the end
+    /// of the request is consumed, so any following request stays buffered
+    /// in the reader.
+    ///
+    /// The request line and headers are read up to the blank line that ends
+    /// them, then the body is read using either `Content-Length` or
+    /// `Transfer-Encoding: chunked`.
+    ///
+    pub fn read_from<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut req = Request::new();
+        let mut budget = limits.max_header_bytes;
+
+        // Request line, eg "GET /game/?user_id=1 HTTP/1.1"
+        let line = match read_line(reader, &mut budget)? {
+            Some(line) => line,
+            None => return Err(ParseError::Closed),
+        };
+        let parts: Vec<&str> = line.split(' ').collect();
+        if parts.len() != 3 || parts[1].is_empty() || !is_token(parts[0]) {
+            return Err(ParseError::BadRequest(format!("Malformed request line {:?}", line)));
+        }
+        req.method = parts[0].to_string();
+        req.version = check_version(parts[2])?.to_string();
+        let url_split: Vec<&str> = parts[1].splitn(2, '?').collect();
+        if url_split.len() > 1 {
+            req.query = Params::parse(url_split[1]);
+        }
+        req.url = url_split[0].to_string();
+
+        // Headers, ended by an empty line
+        loop {
+            let line = match read_line(reader, &mut budget)? {
+                Some(line) => line,
+                None => return Err(ParseError::BadRequest("Stream ended in headers".to_string())),
+            };
+            if line.is_empty() {
+                break;
+            }
+            // obs-fold, a header continued on the next line, is deprecated
+            // and is rejected rather than guessing how to join the lines
+            if line.starts_with(' ') || line.starts_with('\t') {
+                return Err(ParseError::BadRequest("Obsolete line folding in headers".to_string()));
+            }
+            let colon = match line.find(':') {
+                Some(i) => i,
+                None => return Err(ParseError::BadRequest(format!("Malformed header {:?}", line))),
+            };
+            let key = &line[..colon];
+            if !is_token(key) {
+                return Err(ParseError::BadRequest(format!("Malformed header {:?}", line)));
+            }
+            let val = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+            if let Err(e) = req.headers.append(key, val) {
+                return Err(ParseError::BadRequest(e.to_string()));
+            }
+            if key.eq_ignore_ascii_case("Cookie") {
+                cookie::parse_cookies(val, &mut req.cookies);
+            }
+        }
 
-        let mut buffer:[u8; 2048] = [0; 2048]; // limit helps avoid swamping the server. 2048 is typical
+        let body = read_body(reader, &req, limits)?;
 
-        let mut req = Request::new();
-        let mut body = String::new();
-        let mut url = String::new();
-        let mut key = String::new();
-        let mut val = String::new();
-
-        let mut state = State::Method;
-        let mut method_state = MState::Method;
-        let mut headers_state = HState::Key;
-
-        // Begin state machine - run until buffer cleared
-        // The read function fills the provided buffer (and won't overrun), then
-        // returns the length read
-        let read_len = match stream.read(&mut buffer) {
-            Ok(len) => len,
-            Err(_) => return Err("Error reading stream"),
+        // HTTP spec says if a body is sent with a GET request, it should be
+        // ignored, it is still read above so the stream is left at the next request
+        if req.method != "GET" && req.method != "HEAD" && !body.is_empty() {
+            req.body = Some(decode_body(&req, body)?);
+        }
+        Ok(req)
+    }
+}
+
+/// Turn the raw bytes of a body in to the Body variant for its Content-Type.
+/// No Content-Type, or one that isn't understood, gives `Body::Raw`.
+fn decode_body(req: &Request, body: Vec<u8>) -> Result<Body, ParseError> {
+    let content_type = match req.get_header("Content-Type") {
+        Some(ct) => ct,
+        None => return Ok(Body::Raw(body)),
+    };
+    let mut params = content_type.split(';').map(|p| p.trim());
+    let media_type = params.next().unwrap_or("").to_ascii_lowercase();
+    match media_type.as_ref() {
+        "application/x-www-form-urlencoded" => {
+            Ok(Body::Form(Params::parse(&String::from_utf8_lossy(&body))))
+        }
+        "application/json" => {
+            let text = match String::from_utf8(body) {
+                Ok(text) => text,
+                Err(_) => return Err(ParseError::BadRequest("JSON body is not valid UTF-8".to_string())),
+            };
+            match Json::from_str(&text) {
+                Ok(value) => Ok(Body::Json(value)),
+                Err(e) => Err(ParseError::BadRequest(format!("Invalid JSON body: {}", e))),
+            }
+        }
+        "multipart/form-data" => {
+            let boundary = params
+                .filter_map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("boundary") => {
+                            Some(v.trim().trim_matches('"').to_string())
+                        }
+                        _ => None,
+                    }
+                })
+                .next();
+            match boundary {
+                Some(ref b) if !b.is_empty() => parse_multipart(&body, b).map(Body::Multipart),
+                _ => Err(ParseError::BadRequest("multipart/form-data without a boundary".to_string())),
+            }
+        }
+        _ => Ok(Body::Raw(body)),
+    }
+}
+
+/// Split a multipart/form-data body on its boundary, each part has its own
+/// headers (Content-Disposition giving the field name) then its data.
+fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<Part>, ParseError> {
+    let malformed = || ParseError::BadRequest("Malformed multipart body".to_string());
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // Every delimiter after the first is preceded by a CRLF, which isn't part of the data
+    let next_delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut parts = Vec::new();
+    // Anything before the first delimiter is preamble and ignored
+    let mut pos = match find_bytes(body, &delimiter, 0) {
+        Some(i) => i + delimiter.len(),
+        None => return Err(malformed()),
+    };
+    loop {
+        // "--" after a delimiter marks the end of the body
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(malformed());
+        }
+        pos += 2;
+        let head_end = match find_bytes(body, b"\r\n\r\n", pos) {
+            Some(i) => i,
+            None => return Err(malformed()),
+        };
+        let head = String::from_utf8_lossy(&body[pos..head_end]).into_owned();
+        let data_start = head_end + 4;
+        let data_end = match find_bytes(body, &next_delimiter, data_start) {
+            Some(i) => i,
+            None => return Err(malformed()),
         };
-        for n in 0..read_len {
-            let c = buffer[n] as char; // convert byte to char
-            match state {
-                State::Method => {
-                    match method_state {
-                        // Fetch method
-                        MState::Method => {
-                            if c == ' ' {
-                                method_state = MState::Url;
-                            } else {
-                                req.method.push(c);
-                            }
-                        },
-                        MState::Url => {
-                            if c == ' ' {
-                                println!("Url string = {:?}", url);
-                                method_state = MState::Http;
-                            } else {
-                                url.push(c);
-                            }
-                        },
-                        // Ignore HTTP version for now
-                        MState::Http => {
-                            if c == '\r' { method_state = MState::End; }
-                        },
-                        _ => {
-                            if c == '\n' {
-                                state = State::Headers;
-                                let url_split:Vec<&str> = url.split('?').collect();
-                                if url_split.len() > 1 {
-                                    req.body = Some(parse_params(&url_split[1].to_string()));
-                                }
-                                req.url = url_split[0].to_string();
-                                println!("Url string = {:?}", req.url);
-                            }
-                            else {
-                                return Err("Server unable to parse request method");
-                            }
-                        },
+
+        let mut part = Part {
+            name: String::new(),
+            filename: None,
+            content_type: None,
+            data: body[data_start..data_end].to_vec(),
+        };
+        for line in head.split("\r\n") {
+            let mut kv = line.splitn(2, ':');
+            let key = kv.next().unwrap_or("").trim();
+            let val = kv.next().unwrap_or("").trim();
+            if key.eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(val.to_string());
+            } else if key.eq_ignore_ascii_case("Content-Disposition") {
+                for attr in val.split(';').skip(1) {
+                    let mut kv = attr.splitn(2, '=');
+                    let k = kv.next().unwrap_or("").trim();
+                    let v = kv.next().unwrap_or("").trim().trim_matches('"');
+                    match k {
+                        "name" => part.name = v.to_string(),
+                        "filename" => part.filename = Some(v.to_string()),
+                        _ => {}
                     }
                 }
-                State::Headers => {
-                    match headers_state {
-                        // Start of line
-                        HState::Key => {
-                            if c == ' ' {
-                                headers_state = HState::Space;
-                            } else {
-                                key.push(c);
-                            }
-                        },
-                        // Space encountered - remove the ':'
-                        HState::Space => {
-                            headers_state = HState::Value;
-                            key.pop();
-                            val.push(c); } //TODO check this
-                        HState::Value => {
-                            if c == '\r' {
-                                headers_state = HState::Ret
-                            } else {
-                                val.push(c);
-                            }
-                        },
-                        // got '\r' value, c is now '\n', ignore it
-                        HState::Ret => headers_state = HState::Insert,
-                        // end of line or body?
-                        HState::Insert => {
-                            if c == '\r' {
-                                headers_state = HState::End;
-                            } else {
-                                headers_state = HState::Key;
-                            }
-                            req.headers.insert(key, val);
-                            key = String::new();
-                            val = String::new();
-                            key.push(c);
-                        },
-                        // c is now '\n' - end of headers
-                        // HTTP spec says if a body is sent with a GET request,
-                        // it should be ignored.
-                        HState::End => {
-                            if req.method == "POST" {
-                                state = State::Body;
-                            } else {
-                                break;
-                            }
-                        },
-                    }
-                },
-                State::Body => {
-                        body.push(c);
-                },
             }
         }
+        parts.push(part);
+        pos = data_end + next_delimiter.len();
+    }
+}
+
+/// Position of `needle` in `haystack` at or after `from`
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.is_empty() || from > haystack.len() {
+        return None;
+    }
+    haystack[from..].windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
 
-        if body.len() > 0 {
-            // Append
-            if let Some(existing) = req.body.as_mut() { // Option[T].as_mut()->Option(&mut T)
-                for (key,val) in parse_params(&body) {
-                    existing.insert(key,val);
+/// Read one line terminated by LF (a preceding CR is dropped), taking its
+/// length from `budget`. Returns None if the stream ends before any byte
+/// of the line.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut line: Vec<u8> = Vec::new();
+    loop {
+        let (done, used) = {
+            let buf = match reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => return Err(ParseError::Io(e)),
+            };
+            if buf.is_empty() {
+                if line.is_empty() {
+                    return Ok(None);
                 }
+                return Err(ParseError::BadRequest("Stream ended mid line".to_string()));
             }
-            // Or replace if None
-            // Has to be done this way as req.body is borrowed as mut above
-            if req.body == None {
-                req.body = Some(parse_params(&body));
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    line.extend_from_slice(&buf[..i + 1]);
+                    (true, i + 1)
+                }
+                None => {
+                    line.extend_from_slice(buf);
+                    (false, buf.len())
+                }
             }
+        };
+        reader.consume(used);
+        if line.len() > *budget {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        if done {
+            *budget -= line.len();
+            line.pop(); // '\n'
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return match String::from_utf8(line) {
+                Ok(s) => Ok(Some(s)),
+                Err(_) => Err(ParseError::BadRequest("Request head is not valid UTF-8".to_string())),
+            };
         }
-        Ok(req)
     }
 }
 
-/// A helper function to parse params recieved in either URL
-/// or body requests
-///
-/// example:
-///    parse_params("user_id=123&place=3".to_string());
-///
-fn parse_params(string: &String) -> HashMap<String,String> {
-    let mut map:HashMap<String,String> = HashMap::new();
-    // Make an array of strings plit by '&'
-    let pairs:Vec<&str> = string.split('&').collect();
-    // For each string, split by '=' to key/val pair
-    for pair in pairs {
-        println!("{:?}", pair);
-        let keyval:Vec<&str> = pair.split('=').collect();
-        if keyval.len() == 2 {
-           map.insert(keyval[0].to_string(),keyval[1].to_string());
-        } else {
-            println!("Malformed params encountered");
+/// Only HTTP/1.0 and HTTP/1.1 are spoken here
+fn check_version(version: &str) -> Result<&str, ParseError> {
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => Ok(version),
+        v if v.starts_with("HTTP/") => Err(ParseError::VersionNotSupported(v.to_string())),
+        v => Err(ParseError::BadRequest(format!("Malformed HTTP version {:?}", v))),
+    }
+}
+
+/// Is this a valid token as used for methods and header names (RFC 7230)
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| {
+        (b as char).is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    })
+}
+
+/// Read the body of `req` according to its framing headers
+fn read_body<R: BufRead>(reader: &mut R, req: &Request, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    // A proxy in front may frame the body by the other header, so a request
+    // with both (or with lengths that disagree) could hide a second request
+    // in its body. It is refused rather than guessing which was meant.
+    let lengths: Vec<&str> = req.headers.get_all("Content-Length").into_iter()
+        .flat_map(|val| val.split(','))
+        .map(|len| len.trim())
+        .collect();
+    let encodings = req.headers.get_all("Transfer-Encoding");
+    if let Some(te) = encodings.first() {
+        if !lengths.is_empty() {
+            return Err(ParseError::BadRequest("Both Content-Length and Transfer-Encoding sent".to_string()));
+        }
+        if encodings.len() > 1 {
+            return Err(ParseError::BadRequest(format!("Multiple Transfer-Encoding {:?}", encodings)));
+        }
+        if te.eq_ignore_ascii_case("chunked") {
+            return read_chunked(reader, limits);
+        }
+        return Err(ParseError::BadRequest(format!("Unsupported Transfer-Encoding {:?}", te)));
+    }
+    // Only digits, `parse` would also take a leading '+'
+    let mut parsed = Vec::with_capacity(lengths.len());
+    for len in &lengths {
+        match len.parse::<usize>() {
+            Ok(n) if len.bytes().all(|b| b.is_ascii_digit()) => parsed.push(n),
+            _ => return Err(ParseError::BadRequest(format!("Invalid Content-Length {:?}", len))),
+        }
+    }
+    if parsed.iter().any(|&len| len != parsed[0]) {
+        return Err(ParseError::BadRequest(format!("Conflicting Content-Length {:?}", lengths)));
+    }
+    let len = match parsed.first() {
+        Some(&len) => len,
+        // A PUT replaces what is at the url with its body, so must say how
+        // long it is. A POST without a length has no body (RFC 7230 3.3.3),
+        // eg a button that only needs the url.
+        None if req.method == "PUT" => return Err(ParseError::LengthRequired),
+        None => 0,
+    };
+    if len > limits.max_body_bytes {
+        return Err(ParseError::PayloadTooLarge);
+    }
+    let mut body = Vec::with_capacity(len);
+    if let Err(e) = reader.take(len as u64).read_to_end(&mut body) {
+        return Err(ParseError::Io(e));
+    }
+    if body.len() != len {
+        return Err(ParseError::BadRequest("Body shorter than Content-Length".to_string()));
+    }
+    Ok(body)
+}
+
+/// Read a `Transfer-Encoding: chunked` body, each chunk is a hex size line
+/// followed by that many bytes and a CRLF, ending with a zero size chunk
+/// and optional trailers.
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    // Size lines and trailers have their own budget, separate to the body
+    let mut budget = limits.max_header_bytes;
+    loop {
+        let line = match read_line(reader, &mut budget)? {
+            Some(line) => line,
+            None => return Err(ParseError::BadRequest("Stream ended in chunked body".to_string())),
+        };
+        // Chunk extensions after ';' are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(ParseError::BadRequest(format!("Invalid chunk size {:?}", line))),
+        };
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        let start = body.len();
+        if let Err(e) = reader.take(size as u64).read_to_end(&mut body) {
+            return Err(ParseError::Io(e));
+        }
+        if body.len() - start != size {
+            return Err(ParseError::BadRequest("Chunk shorter than its size".to_string()));
+        }
+        match read_line(reader, &mut budget)? {
+            Some(ref end) if end.is_empty() => {}
+            _ => return Err(ParseError::BadRequest("Chunk not followed by CRLF".to_string())),
+        }
+    }
+    // Trailers are read and discarded up to the final empty line
+    loop {
+        match read_line(reader, &mut budget)? {
+            Some(ref line) if line.is_empty() => return Ok(body),
+            Some(_) => {}
+            None => return Err(ParseError::BadRequest("Stream ended in trailers".to_string())),
+        }
+    }
+}
+
+/// Shared by the param getters on Request, a missing or empty param
+/// becomes a 422 Response.
+#[allow(clippy::result_large_err)]
+fn non_empty<'a>(param: &str, val: Option<&'a String>) -> Result<&'a String, Response> {
+    match val {
+        Some(p) => {
+            if p.is_empty() {
+                return Err(Status::faulty_query(&format!("{:?} was empty", param)))
+            }
+            Ok(p)
+        },
+        None => Err(Status::faulty_query(&format!("No {:?} submitted in query", param))),
+    }
+}
+
+/// Params recieved in either the URL or a form body, decoded from
+/// `application/x-www-form-urlencoded`. Pairs are kept in the order
+/// they were sent, and a key may appear more than once.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+impl Params {
+    pub fn new() -> Params {
+        Params { pairs: Vec::new() }
+    }
+    /// Decode a query string or form body
+    ///
+    /// example:
+    ///    let params = Params::parse("user_id=123&place=3&name=a+b%21");
+    ///    assert_eq!(params.get("name").unwrap(), "a b!");
+    ///
+    /// A key with no '=' has an empty value, and only the first '=' splits
+    /// the key from the value.
+    pub fn parse(string: &str) -> Params {
+        let mut params = Params::new();
+        // Empty pieces, eg from "a=1&&b=2", are skipped
+        for pair in string.split('&').filter(|p| !p.is_empty()) {
+            let mut keyval = pair.splitn(2, '=');
+            let key = keyval.next().unwrap_or("");
+            let val = keyval.next().unwrap_or("");
+            params.append(&percent_decode(key), &percent_decode(val));
+        }
+        params
+    }
+    /// The first value for a key
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.pairs.iter().find(|&(k, _)| k == key).map(|(_, v)| v)
+    }
+    /// Every value for a key, in the order sent
+    pub fn get_all(&self, key: &str) -> Vec<&String> {
+        self.pairs.iter().filter(|&(k, _)| k == key).map(|(_, v)| v).collect()
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+    /// Add a value, keeping any existing values for the key
+    pub fn append(&mut self, key: &str, val: &str) {
+        self.pairs.push((key.to_string(), val.to_string()));
+    }
+    pub fn iter<'a>(&'a self) -> ::std::slice::Iter<'a, (String, String)> {
+        self.pairs.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+    /// A JSON object of the params, so they can be decoded like a JSON body.
+    /// A key sent more than once becomes an array of its values.
+    pub fn to_json(&self) -> Json {
+        let to_value = |val: &String| match val.as_ref() {
+            "true" => Json::Boolean(true),
+            "false" => Json::Boolean(false),
+            _ => Json::String(val.clone()),
+        };
+        let mut object = BTreeMap::new();
+        for (key, _) in &self.pairs {
+            if object.contains_key(key) {
+                continue;
+            }
+            let vals = self.get_all(key);
+            let value = if vals.len() == 1 {
+                to_value(vals[0])
+            } else {
+                Json::Array(vals.into_iter().map(&to_value).collect())
+            };
+            object.insert(key.clone(), value);
         }
+        Json::Object(object)
+    }
+}
+
+/// Decode '+' to a space and "%XX" escapes to bytes, the bytes are then
+/// read as UTF-8 with any invalid sequences replaced. A '%' not followed
+/// by two hex digits is kept as is.
+pub fn percent_decode(string: &str) -> String {
+    let bytes = string.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_val(bytes[i + 1]), hex_val(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        out.push(high * 16 + low);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
     }
-    map
 }
 
 /// A standard structure for the server Response
@@ -232,16 +894,48 @@ fn parse_params(string: &String) -> HashMap<String,String> {
 /// `status`, `header`, and `body` need to be used
 /// to insert content.
 ///
+/// Helpers that fail with the Response to send return it as their Err, and
+/// allow `clippy::result_large_err` one by one since it is only built on
+/// the way out.
+///
 pub struct Response {
     pub code   : String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body   : Option<Vec<u8>>,
+    /// A body read as it is written, sent instead of `body`
+    pub stream : Option<BodyStream>,
+    /// Takes over the connection once this response has been written,
+    /// see `Response::upgrade`
+    pub upgrade: Option<Upgrade>,
+    /// Leave the body out when writing, for an answer to a HEAD request.
+    /// The headers are still those the body would be sent with.
+    pub head   : bool,
+}
+
+/// What a connection is handed to after a `101 Switching Protocols`
+pub type Upgrade = Box<dyn FnOnce(TcpStream) + Send>;
+
+/// A body streamed from a reader by `Response::write_to`. With a known
+/// length it is sent with `Content-Length`, otherwise it is sent chunked
+/// (or until the connection closes with `Connection: close`) and every
+/// chunk is flushed as soon as it is read.
+pub struct BodyStream {
+    pub reader: Box<dyn Read + Send>,
+    pub len   : Option<u64>,
+}
+impl Default for Response {
+    fn default() -> Response {
+        Response::new()
+    }
 }
 impl Response {
     pub fn new() -> Response {
         Response { code: String::new(),
-                   headers: HashMap::new(),
-                   body: None, }
+                   headers: Headers::new(),
+                   body: None,
+                   stream: None,
+                   upgrade: None,
+                   head: false, }
     }
     
     /// Modify the status string with a status code and optional message
@@ -255,14 +949,45 @@ impl Response {
         self.code = ver+status+" "+expl.unwrap_or("");
     }
     
-    /// Insert headers in the format of key, value
+    /// Insert headers in the format of key, value, replacing any header
+    /// of the same name. A header that could inject in to the response is
+    /// dropped, use `headers.insert` to handle the error instead.
     ///
     /// example:
     ///    let mut response = Response::new();
     ///    response.header("Content-Type", "text/html");
     ///
     pub fn header(&mut self, key: &str, val: &str) {
-        self.headers.insert(key.to_string(),val.to_string());
+        if let Err(e) = self.headers.insert(key, val) {
+            println!("Dropped header: {}", e);
+        }
+    }
+    
+    /// Add a header without replacing others of the same name
+    ///
+    /// example:
+    ///    let mut response = Response::new();
+    ///    response.add_header("Vary", "Accept");
+    ///    response.add_header("Vary", "Cookie");
+    ///
+    pub fn add_header(&mut self, key: &str, val: &str) {
+        if let Err(e) = self.headers.append(key, val) {
+            println!("Dropped header: {}", e);
+        }
+    }
+    
+    /// Add a cookie to be set on the client, any number can be set
+    ///
+    /// example:
+    ///    let mut response = Response::new();
+    ///    response.set_cookie(Cookie::new("theme", "dark")).unwrap();
+    ///
+    pub fn set_cookie(&mut self, cookie: Cookie) -> Result<(), String> {
+        cookie.validate()?;
+        match self.headers.append("Set-Cookie", &cookie.to_header()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
     }
     
     /// Add/Remove a body from the Response
@@ -274,6 +999,7 @@ impl Response {
     ///
     pub fn body(&mut self, text: Vec<u8>) {
         self.body = Some(text);
+        self.stream = None;
     }
     pub fn body_len(&self) -> u32 {
         match self.body.as_ref() {
@@ -281,27 +1007,212 @@ impl Response {
             None => 0,
         }
     }
+
+    /// Stream the body from a reader while the response is written, `len`
+    /// is the number of bytes it will give if known
+    ///
+    /// example:
+    ///    let file = File::open("favicon.ico").unwrap();
+    ///    let len = file.metadata().unwrap().len();
+    ///    response.stream(file, Some(len));
+    ///
+    pub fn stream<R: Read + Send + 'static>(&mut self, reader: R, len: Option<u64>) {
+        self.stream = Some(BodyStream { reader: Box::new(reader), len });
+        self.body = None;
+    }
+
+    /// Switch the connection to another protocol, eg a WebSocket. Once the
+    /// response (a 101) is written the server stops reading requests and
+    /// passes the connection to `handler`, which is then responsible for it.
+    ///
+    /// example:
+    ///    let mut response = Status::switching_protocols();
+    ///    response.header("Upgrade", "websocket");
+    ///    response.upgrade(move |stream| serve_socket(stream));
+    ///
+    pub fn upgrade<F: FnOnce(TcpStream) + Send + 'static>(&mut self, handler: F) {
+        self.upgrade = Some(Box::new(handler));
+    }
+
+    /// The numeric status code, eg 404, or 0 if no status has been set
+    pub fn status_code(&self) -> u16 {
+        self.code.split(' ').nth(1).and_then(|c| c.parse().ok()).unwrap_or(0)
+    }
+
+    /// Write the status line, headers and body to `out`. The body is sent
+    /// as raw bytes, and `Content-Length` (or `Transfer-Encoding: chunked`
+    /// for a stream of unknown length) is set here to match it, replacing
+    /// any that were set by hand. With `head` set only the headers are sent.
+    ///
+    /// example:
+    ///    let mut response = Status::ok();
+    ///    response.body(png_bytes);
+    ///    response.write_to(&mut stream).unwrap();
+    ///
+    pub fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let code = self.status_code();
+        // 1xx, 204 and 304 responses never have a body
+        let bodiless = code < 200 || code == 204 || code == 304;
+        let mut until_close = false;
+        let mut chunked = false;
+        if !bodiless {
+            self.headers.remove("Transfer-Encoding");
+            match self.stream {
+                Some(BodyStream { len: Some(len), .. }) => {
+                    self.header("Content-Length", &len.to_string());
+                }
+                Some(BodyStream { len: None, .. }) => {
+                    self.headers.remove("Content-Length");
+                    let close = match self.headers.get("Connection") {
+                        Some(val) => val.eq_ignore_ascii_case("close"),
+                        None => false,
+                    };
+                    if close {
+                        until_close = true;
+                    } else {
+                        chunked = true;
+                        self.header("Transfer-Encoding", "chunked");
+                    }
+                }
+                None => {
+                    let body_len = &self.body_len().to_string();
+                    self.header("Content-Length", body_len);
+                }
+            }
+        }
+
+        let mut head = self.code.clone() + "\r\n";
+        for (key, val) in self.headers.iter() {
+            head += &(key.clone() + ": " + val + "\r\n");
+        }
+        // A blank line always ends the headers, even with no body
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())?;
+
+        if bodiless || self.head {
+            self.stream = None;
+            return out.flush();
+        }
+        if let Some(ref body) = self.body {
+            out.write_all(body)?;
+        }
+        if let Some(mut stream) = self.stream.take() {
+            if chunked {
+                write_chunked(&mut stream.reader, out)?;
+            } else if until_close {
+                io::copy(&mut stream.reader, out)?;
+            } else {
+                let len = stream.len.unwrap_or(0);
+                let sent = io::copy(&mut stream.reader.take(len), out)?;
+                // The client would wait for bytes that will never come
+                if sent < len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "Body stream ended before its length"));
+                }
+            }
+        }
+        out.flush()
+    }
 }
-impl ToString for Response {
-    fn to_string(&self) -> String {
-        let line_end = String::from("\r\n");
-        let mut string: String = self.code.to_string() + &line_end;
-        for (key, val) in &self.headers {
-            string += &(key.clone() + ": " + val + &line_end);
+/// For logging, a body that isn't text is shown lossily and a streamed body
+/// is left out, use `write_to` to send a Response.
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_end = "\r\n";
+        write!(f, "{}{}", self.code, line_end)?;
+        for (key, val) in self.headers.iter() {
+            write!(f, "{}: {}{}", key, val, line_end)?;
         }
-        match self.body {
-            Some(ref b) => {string.push_str(&line_end);
-                            string.push_str(str::from_utf8(&b).unwrap());
-                            string.push_str(&line_end)},
-            None => {},
+        // A blank line always ends the headers, even with no body
+        f.write_str(line_end)?;
+        if let Some(ref b) = self.body {
+            f.write_str(&String::from_utf8_lossy(b))?;
         }
-        string
+        Ok(())
+    }
+}
+
+/// Copy `reader` to `out` as chunks, flushing each so a slow stream (eg
+/// server-sent events) reaches the client as it is produced
+fn write_chunked<W: Write>(reader: &mut dyn Read, out: &mut W) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(format!("{:x}\r\n", n).as_bytes())?;
+        out.write_all(&buf[..n])?;
+        out.write_all(b"\r\n")?;
+        out.flush()?;
+    }
+    out.write_all(b"0\r\n\r\n")
+}
+
+/// Format a time as an HTTP-date (RFC 7231), eg "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    };
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (hour, min, sec) = (rem / 3600, rem % 3600 / 60, rem % 60);
+    // 1970-01-01 was a Thursday
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][days.rem_euclid(7) as usize];
+
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let month_name = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                      "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"][(month - 1) as usize];
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", weekday, day, month_name, year, hour, min, sec)
+}
+
+/// Parse an HTTP-date in the format `http_date` writes, None if it isn't one.
+/// The older RFC 850 and asctime formats are not accepted.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = date.split(' ').collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day: i64 = parts[1].parse().ok()?;
+    let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                 "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"].iter().position(|m| *m == parts[2])? as i64 + 1;
+    let year: i64 = parts[3].parse().ok()?;
+    let time: Vec<i64> = parts[4].split(':').filter_map(|t| t.parse().ok()).collect();
+    if time.len() != 3 || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+
+    // Civil date to days, the inverse of the algorithm in `http_date`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    if secs < 0 {
+        return None;
     }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
 }
 
 /// Some standard templates for responses.
 /// Most won't need to be edited except for cases
 /// where a body may be desirable, or it's an Ok.
+#[allow(non_snake_case)]
 pub mod Status {
     use ::Response;
     
@@ -312,24 +1223,120 @@ pub mod Status {
         res.body(text.as_bytes().to_vec());
         res
     }
+    /// No body is sent with a 101, so it has no Content-Type
+    pub fn switching_protocols() -> Response {
+        let mut res = Response::new();
+        res.status("101", Some("Switching Protocols"));
+        res
+    }
     pub fn ok() -> Response {
         let mut res = Response::new();
         res.status("200", Some("Ok"));
         res.header("Content-Type", "text/html");
         res
     }
+    pub fn created() -> Response {
+        let mut res = Response::new();
+        res.status("201", Some("Created"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    /// No body is sent with a 204, so it has no Content-Type
+    pub fn no_content() -> Response {
+        let mut res = Response::new();
+        res.status("204", Some("No Content"));
+        res
+    }
+    pub fn partial_content() -> Response {
+        let mut res = Response::new();
+        res.status("206", Some("Partial Content"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn moved_permanently() -> Response {
+        let mut res = Response::new();
+        res.status("301", Some("Moved Permanently"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn see_other() -> Response {
+        let mut res = Response::new();
+        res.status("303", Some("See Other"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    /// No body is sent with a 304, so it has no Content-Type
+    pub fn not_modified() -> Response {
+        let mut res = Response::new();
+        res.status("304", Some("Not Modified"));
+        res
+    }
     pub fn bad_request() -> Response {
         let mut res = Response::new();
         res.status("400", Some("Bad Request"));
         res.header("Content-Type", "text/html");
         res
     }
+    pub fn forbidden() -> Response {
+        let mut res = Response::new();
+        res.status("403", Some("Forbidden"));
+        res.header("Content-Type", "text/html");
+        res
+    }
     pub fn not_found()  -> Response {
         let mut res = Response::new();
         res.status("404", Some("Not Found"));
         res.header("Content-Type", "text/html");
         res
     }
+    pub fn method_not_allowed() -> Response {
+        let mut res = Response::new();
+        res.status("405", Some("Method Not Allowed"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn conflict() -> Response {
+        let mut res = Response::new();
+        res.status("409", Some("Conflict"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn length_required() -> Response {
+        let mut res = Response::new();
+        res.status("411", Some("Length Required"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn payload_too_large() -> Response {
+        let mut res = Response::new();
+        res.status("413", Some("Payload Too Large"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn range_not_satisfiable() -> Response {
+        let mut res = Response::new();
+        res.status("416", Some("Range Not Satisfiable"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn upgrade_required() -> Response {
+        let mut res = Response::new();
+        res.status("426", Some("Upgrade Required"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn header_fields_too_large() -> Response {
+        let mut res = Response::new();
+        res.status("431", Some("Request Header Fields Too Large"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn unsupported_media_type() -> Response {
+        let mut res = Response::new();
+        res.status("415", Some("Unsupported Media Type"));
+        res.header("Content-Type", "text/html");
+        res
+    }
     pub fn internal_error() -> Response {
         let mut res = Response::new();
         res.status("500", Some("Internal Server Error"));
@@ -342,6 +1349,18 @@ pub mod Status {
         res.header("Content-Type", "text/html");
         res
     }
+    pub fn service_unavailable() -> Response {
+        let mut res = Response::new();
+        res.status("503", Some("Service Unavailable"));
+        res.header("Content-Type", "text/html");
+        res
+    }
+    pub fn version_not_supported() -> Response {
+        let mut res = Response::new();
+        res.status("505", Some("HTTP Version Not Supported"));
+        res.header("Content-Type", "text/html");
+        res
+    }
     pub fn unkown_error() -> Response {
         let mut res = Response::new();
         res.status("520", Some("Unkown Error"));
@@ -349,3 +1368,275 @@ pub mod Status {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes(), &Limits::new())
+    }
+
+    /// The status a request is answered with when it can't be parsed
+    fn refused(raw: &str) -> u16 {
+        match parse(raw) {
+            Ok(_) => panic!("Parsed {:?}", raw),
+            Err(e) => e.response().unwrap().status_code(),
+        }
+    }
+
+    fn raw_body(req: &Request) -> &[u8] {
+        match req.body {
+            Some(Body::Raw(ref bytes)) => bytes,
+            ref other => panic!("Expected a raw body, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn get_with_query() {
+        let req = parse("GET /game/?user_id=1 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.url, "/game/");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.get_header("host").map(|h| h.as_str()), Some("localhost"));
+        assert!(req.body.is_none());
+    }
+
+    #[test]
+    fn body_by_content_length() {
+        let mut raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n".as_bytes();
+        let req = Request::read_from(&mut raw, &Limits::new()).unwrap();
+        assert_eq!(raw_body(&req), b"hello");
+        // The next request is left in the reader
+        let next = Request::read_from(&mut raw, &Limits::new()).unwrap();
+        assert_eq!(next.method, "GET");
+    }
+
+    #[test]
+    fn chunked_body() {
+        let req = parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n").unwrap();
+        assert_eq!(raw_body(&req), b"hello, world");
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n0\r\n\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn duplicate_content_length() {
+        let req = parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert_eq!(raw_body(&req), b"hi");
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 5\r\n\r\nhello"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 2, 5\r\n\r\nhello"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"), 400);
+        // Conflicts are found across every header, not just the first two
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\
+                            Content-Length: 2, 3\r\n\r\nhi"), 400);
+        let req = parse("POST / HTTP/1.1\r\nContent-Length: 2, 2\r\nContent-Length: 02\r\n\r\nhi").unwrap();
+        assert_eq!(raw_body(&req), b"hi");
+    }
+
+    #[test]
+    fn content_length_is_digits() {
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nhi"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: -2\r\n\r\nhi"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 2 2\r\n\r\nhi"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 0x2\r\n\r\nhi"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: \r\n\r\nhi"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 2,\r\n\r\nhi"), 400);
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding() {
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+                            0\r\n\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n\
+                            0\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn multiple_transfer_encodings() {
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n\
+                            0\r\n\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n\
+                            0\r\n\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert_eq!(refused("GET /\r\n\r\n"), 400);
+        assert_eq!(refused("GET / HTTP/1.1 extra\r\n\r\n"), 400);
+        assert_eq!(refused("GET / HTTP/1.1\r\nNo colon\r\n\r\n"), 400);
+        assert_eq!(refused("GET / HTTP/1.1\r\nX-A: 1\r\n  folded\r\n\r\n"), 400);
+        assert_eq!(refused("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi"), 400);
+        assert!(matches!(parse(""), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn length_required() {
+        assert_eq!(refused("PUT / HTTP/1.1\r\n\r\n"), 411);
+        let req = parse("POST /api/games HTTP/1.1\r\n\r\n").unwrap();
+        assert!(req.body.is_none());
+    }
+
+    #[test]
+    fn size_limits() {
+        let limits = Limits::new();
+        let big = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", limits.max_body_bytes + 1);
+        assert_eq!(refused(&big), 413);
+        let big_chunk = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                                limits.max_body_bytes + 1);
+        assert_eq!(refused(&big_chunk), 413);
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(limits.max_header_bytes));
+        assert_eq!(refused(&long_header), 431);
+        // A body exactly at the limit is fine
+        let body = "a".repeat(limits.max_body_bytes);
+        let req = parse(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)).unwrap();
+        assert_eq!(raw_body(&req).len(), limits.max_body_bytes);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert_eq!(refused("GET / HTTP/2.0\r\n\r\n"), 505);
+        assert_eq!(refused("GET / SPDY/3\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a+b+%2B"), "a b +");
+        assert_eq!(percent_decode("%41%62%3d%3D"), "Ab==");
+        assert_eq!(percent_decode("%E2%9C%93"), "\u{2713}");
+        // A '%' without two hex digits after it is kept
+        assert_eq!(percent_decode("100%zz"), "100%zz");
+        assert_eq!(percent_decode("%"), "%");
+        assert_eq!(percent_decode("a%"), "a%");
+        assert_eq!(percent_decode("a%4"), "a%4");
+        assert_eq!(percent_decode("%%41"), "%A");
+        // Invalid UTF-8 is replaced rather than refused
+        assert_eq!(percent_decode("a%FFb"), "a\u{FFFD}b");
+        assert_eq!(percent_decode("%E2%9C"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn params_parse() {
+        let params = Params::parse("user_id=123&name=a+b%21&&flag&x=1=2&user_id=456&%6Bey=v%");
+        assert_eq!(params.len(), 6);
+        assert_eq!(params.get("name").map(|v| v.as_str()), Some("a b!"));
+        assert_eq!(params.get("flag").map(|v| v.as_str()), Some(""));
+        assert_eq!(params.get("x").map(|v| v.as_str()), Some("1=2"));
+        assert_eq!(params.get("key").map(|v| v.as_str()), Some("v%"));
+        assert_eq!(params.get_all("user_id"), vec!["123", "456"]);
+        assert!(!params.contains_key("missing"));
+        assert!(Params::parse("").is_empty());
+
+        let json = Params::parse("a=1&b=true&a=2").to_json();
+        assert_eq!(json.find("b"), Some(&Json::Boolean(true)));
+        assert_eq!(json.find("a"), Some(&Json::Array(vec![Json::String("1".to_string()),
+                                                          Json::String("2".to_string())])));
+    }
+
+    /// Everything `write_to` sends for the response
+    fn written(response: &mut Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// A reader giving a few bytes at a time, like a slow stream
+    struct Trickle(Vec<u8>);
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(4);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn write_body_with_length() {
+        let mut response = Status::ok();
+        response.header("Content-Length", "999");
+        response.header("Transfer-Encoding", "chunked");
+        response.body(b"hello".to_vec());
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello");
+
+        let mut response = Status::ok();
+        assert_eq!(written(&mut response), "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn write_streamed_body() {
+        // With a length, sent as is
+        let mut response = Status::ok();
+        response.stream(Trickle(b"hello, world".to_vec()), Some(12));
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nContent-Length: 12\r\n\r\nhello, world");
+
+        // Without, sent chunked as it is read
+        let mut response = Status::ok();
+        response.header("Content-Length", "12");
+        response.stream(Trickle(b"hello, world".to_vec()), None);
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    4\r\nhell\r\n4\r\no, w\r\n4\r\norld\r\n0\r\n\r\n");
+
+        // Or until the connection closes
+        let mut response = Status::ok();
+        response.header("Connection", "close");
+        response.stream(Trickle(b"hello".to_vec()), None);
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nConnection: close\r\n\r\nhello");
+    }
+
+    #[test]
+    fn write_short_stream_is_an_error() {
+        let mut response = Status::ok();
+        response.stream(Trickle(b"hello".to_vec()), Some(10));
+        let mut out = Vec::new();
+        let err = response.write_to(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(String::from_utf8(out).unwrap().ends_with("Content-Length: 10\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn write_bodiless_statuses() {
+        let mut response = Status::switching_protocols();
+        response.header("Upgrade", "websocket");
+        assert_eq!(written(&mut response), "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n");
+
+        let mut response = Status::no_content();
+        response.body(b"dropped".to_vec());
+        assert_eq!(written(&mut response), "HTTP/1.1 204 No Content\r\n\r\n");
+
+        let mut response = Status::not_modified();
+        response.header("ETag", "\"5-1\"");
+        response.stream(Trickle(b"dropped".to_vec()), Some(7));
+        assert_eq!(written(&mut response), "HTTP/1.1 304 Not Modified\r\nETag: \"5-1\"\r\n\r\n");
+        assert!(response.stream.is_none());
+    }
+
+    #[test]
+    fn write_head() {
+        // The headers are those a GET gets, without the body
+        let mut response = Status::ok();
+        response.body(b"hello".to_vec());
+        response.head = true;
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\n");
+
+        let mut response = Status::ok();
+        response.stream(Trickle(b"hello".to_vec()), Some(5));
+        response.head = true;
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\n");
+
+        let mut response = Status::ok();
+        response.stream(Trickle(b"hello".to_vec()), None);
+        response.head = true;
+        assert_eq!(written(&mut response),
+                   "HTTP/1.1 200 Ok\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n");
+    }
+}
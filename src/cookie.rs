@@ -0,0 +1,221 @@
+//! Cookies sent by the client, and `Set-Cookie` for the Response
+//!
+//! example:
+//!    let mut cookie = Cookie::new("session", "abc");
+//!    cookie.path = Some("/".to_string());
+//!    cookie.max_age = Some(3600);
+//!    cookie.http_only = true;
+//!    cookie.same_site = Some(SameSite::Lax);
+//!    response.set_cookie(cookie).unwrap();
+//!
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+use http_date;
+
+/// Whether the browser sends the cookie on requests from other sites
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to be set on the client, fields left as None or false are
+/// not sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name     : String,
+    pub value    : String,
+    pub path     : Option<String>,
+    pub domain   : Option<String>,
+    /// Seconds until the cookie expires, 0 or less deletes it
+    pub max_age  : Option<i64>,
+    pub expires  : Option<SystemTime>,
+    pub http_only: bool,
+    pub secure   : bool,
+    pub same_site: Option<SameSite>,
+}
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name     : name.to_string(),
+            value    : value.to_string(),
+            path     : None,
+            domain   : None,
+            max_age  : None,
+            expires  : None,
+            http_only: false,
+            secure   : false,
+            same_site: None,
+        }
+    }
+    /// A cookie that tells the client to delete `name`
+    pub fn removal(name: &str) -> Cookie {
+        let mut cookie = Cookie::new(name, "");
+        cookie.path = Some("/".to_string());
+        cookie.max_age = Some(0);
+        cookie
+    }
+    /// Check the name, value and attributes can't break out of the header,
+    /// per the cookie grammar in RFC 6265
+    pub fn validate(&self) -> Result<(), String> {
+        if !::is_token(&self.name) {
+            return Err(format!("Invalid cookie name {:?}", self.name));
+        }
+        let value_ok = self.value.bytes().all(|b| {
+            matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+        });
+        if !value_ok {
+            return Err(format!("Invalid cookie value {:?}", self.value));
+        }
+        let attr_ok = |attr: &Option<String>| match *attr {
+            Some(ref a) => a.bytes().all(|b| b >= 0x20 && b != 0x7f && b != b';'),
+            None => true,
+        };
+        if !attr_ok(&self.path) || !attr_ok(&self.domain) {
+            return Err(format!("Invalid path or domain for cookie {:?}", self.name));
+        }
+        Ok(())
+    }
+    /// The value of a `Set-Cookie` header for this cookie
+    pub fn to_header(&self) -> String {
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(ref path) = self.path {
+            header.push_str(&format!("; Path={}", path));
+        }
+        if let Some(ref domain) = self.domain {
+            header.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", http_date(expires)));
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => header.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => header.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => header.push_str("; SameSite=None"),
+            None => {}
+        }
+        header
+    }
+}
+
+/// Parse the value of a `Cookie` header, "a=1; b=2", in to a map. Pairs
+/// without a '=' are skipped, and a quoted value has its quotes removed.
+pub fn parse_cookies(header: &str, cookies: &mut HashMap<String, String>) {
+    for pair in header.split(';') {
+        let mut kv = pair.splitn(2, '=');
+        let name = kv.next().unwrap_or("").trim();
+        let value = match kv.next() {
+            Some(value) => value.trim(),
+            None => continue,
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+        // The first cookie of a name is the most specific, so it wins
+        cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn parse(header: &str) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        parse_cookies(header, &mut cookies);
+        cookies
+    }
+
+    #[test]
+    fn parses_pairs() {
+        let cookies = parse("session=abc;theme = dark ; n=1=2");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["session"], "abc");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["n"], "1=2");
+    }
+
+    #[test]
+    fn quoted_values_lose_their_quotes() {
+        let cookies = parse("a=\"quoted\"; b=\"\"; c=\"; d=\"half");
+        assert_eq!(cookies["a"], "quoted");
+        assert_eq!(cookies["b"], "");
+        // A lone or unmatched quote is kept
+        assert_eq!(cookies["c"], "\"");
+        assert_eq!(cookies["d"], "\"half");
+    }
+
+    #[test]
+    fn first_of_a_name_wins() {
+        let mut cookies = parse("session=path; session=root");
+        assert_eq!(cookies["session"], "path");
+        // Including over a later Cookie header
+        parse_cookies("session=later", &mut cookies);
+        assert_eq!(cookies["session"], "path");
+    }
+
+    #[test]
+    fn empty_pairs_are_skipped() {
+        let cookies = parse(";; flag; =nameless; a=; b=2;");
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["a"], "");
+        assert_eq!(cookies["b"], "2");
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn header_attributes() {
+        let mut cookie = Cookie::new("session", "abc");
+        assert_eq!(cookie.to_header(), "session=abc");
+        cookie.path = Some("/".to_string());
+        cookie.domain = Some("example.com".to_string());
+        cookie.max_age = Some(3600);
+        cookie.expires = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+        cookie.http_only = true;
+        cookie.secure = true;
+        cookie.same_site = Some(SameSite::Lax);
+        assert_eq!(cookie.to_header(),
+                   "session=abc; Path=/; Domain=example.com; Max-Age=3600; \
+                    Expires=Sun, 06 Nov 1994 08:49:37 GMT; HttpOnly; Secure; SameSite=Lax");
+        cookie.same_site = Some(SameSite::Strict);
+        assert!(cookie.to_header().ends_with("; SameSite=Strict"));
+        cookie.same_site = Some(SameSite::None);
+        assert!(cookie.to_header().ends_with("; SameSite=None"));
+
+        assert_eq!(Cookie::removal("session").to_header(), "session=; Path=/; Max-Age=0");
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Cookie::new("session", "abc-123_!").validate().is_ok());
+        assert!(Cookie::removal("session").validate().is_ok());
+        assert!(Cookie::new("", "abc").validate().is_err());
+        assert!(Cookie::new("se;ssion", "abc").validate().is_err());
+        for value in &["a b", "a;b", "a,b", "a\"b", "a\\b", "a\r\nSet-Cookie: x=1"] {
+            assert!(Cookie::new("session", value).validate().is_err(), "{:?}", value);
+        }
+        let mut cookie = Cookie::new("session", "abc");
+        cookie.path = Some("/; Domain=evil.com".to_string());
+        assert!(cookie.validate().is_err());
+        cookie.path = None;
+        cookie.domain = Some("example.com\n".to_string());
+        assert!(cookie.validate().is_err());
+    }
+}
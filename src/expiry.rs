@@ -0,0 +1,213 @@
+//! Keeps the number of games the game_server holds in check
+//!
+//! Every game notes when it was last played or looked at. A reaper thread
+//! wakes every GAME_REAP_SECS (default 60) and removes the games no one has
+//! touched for GAME_TTL_SECS (default 86400, a day). MAX_GAMES (default
+//! 10000) caps how many games are kept at once, a new game past the cap
+//! pushes out the one touched longest ago. Games removed either way are
+//! gone as if abandoned, and are counted in the `Stats` the web_server can
+//! ask for.
+//!
+//! The reaper also forgets user ids given out more than GAME_TTL_SECS ago
+//! that no game left has a seat for, so visitors who never play (or whose
+//! games have all gone) don't pile up.
+//!
+
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use common::shutdown;
+use store::Change;
+use {TicTacBoard, TicTacGame, now_millis};
+
+/// How long games last and how many are kept
+pub struct Limits {
+    /// Milliseconds a game can go untouched before it is removed
+    pub ttl_ms   : u64,
+    /// The most games kept at once
+    pub max_games: usize,
+    /// Seconds between each run of the reaper
+    pub interval : u64,
+}
+impl Limits {
+    /// The limits set by GAME_TTL_SECS, MAX_GAMES and GAME_REAP_SECS
+    pub fn from_env() -> Limits {
+        let var = |name: &str, default: u64| match env::var(name) {
+            Ok(val) => val.parse().unwrap_or(default),
+            Err(_) => default,
+        };
+        Limits {
+            ttl_ms   : var("GAME_TTL_SECS", 86400) * 1000,
+            max_games: var("MAX_GAMES", 10000) as usize,
+            interval : var("GAME_REAP_SECS", 60),
+        }
+    }
+}
+
+impl TicTacGame {
+    /// Remove every game that hasn't been touched within the TTL, then the
+    /// users older than the TTL without a game. Returns how many games and
+    /// users were removed.
+    pub fn reap(&self) -> (usize, usize) {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        let cutoff = now_millis().saturating_sub(self.limits.ttl_ms);
+        let idle: Vec<u32> = guard.board.iter()
+            .filter(|&(_, game)| game.last_active < cutoff)
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in idle.iter() {
+            self.remove(&mut guard, id);
+        }
+        self.expired.fetch_add(idle.len(), Ordering::SeqCst);
+
+        let playing: HashSet<u32> = guard.board.values()
+            .flat_map(|game| Some(game.owner).into_iter().chain(game.guest))
+            .collect();
+        let gone: Vec<u32> = guard.users.iter()
+            .filter(|&(id, &given)| given < cutoff && !playing.contains(id))
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in gone.iter() {
+            guard.apply(Change::RemoveUser(id));
+            self.record(&guard, Change::RemoveUser(id));
+        }
+        (idle.len(), gone.len())
+    }
+
+    /// Make room for one more game, removing the games touched longest ago
+    /// while there are `max_games` or more. Called with the board locked.
+    pub fn make_room(&self, board: &mut TicTacBoard) {
+        while !board.board.is_empty() && board.board.len() >= self.limits.max_games {
+            // A scan is fine at the sizes MAX_GAMES allows, and costs
+            // nothing until the cap is reached
+            let oldest = match board.board.iter().min_by_key(|&(_, game)| game.last_active) {
+                Some((&id, _)) => id,
+                None => return,
+            };
+            self.remove(board, oldest);
+            self.evicted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn remove(&self, board: &mut TicTacBoard, game_id: u32) {
+        board.apply(Change::Remove(game_id));
+        self.record(board, Change::Remove(game_id));
+    }
+}
+
+/// Start the thread that removes idle games, it stops once a shutdown is
+/// requested
+pub fn spawn_reaper(game: Arc<TicTacGame>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut waited = 0;
+        // Sleep a second at a time so a shutdown isn't held up
+        while !shutdown::requested() {
+            thread::sleep(Duration::from_secs(1));
+            waited += 1;
+            if waited < game.limits.interval {
+                continue;
+            }
+            waited = 0;
+            let (games, users) = game.reap();
+            if games > 0 {
+                println!("Removed {} idle games, {} left ({} idle and {} over the limit removed since start up)",
+                         games, game.data.lock().unwrap().board.len(),
+                         game.expired.load(Ordering::SeqCst), game.evicted.load(Ordering::SeqCst));
+            }
+            if users > 0 {
+                println!("Forgot {} users without a game, {} left", users, game.data.lock().unwrap().users.len());
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{BoardSize, Difficulty, Opponent};
+    use store::MemoryStore;
+
+    fn tictac(max_games: usize) -> TicTacGame {
+        let limits = Limits { ttl_ms: 60 * 1000, max_games, interval: 60 };
+        TicTacGame::open(Box::new(MemoryStore), limits).unwrap()
+    }
+
+    /// A new game for a new user, last touched at `last_active`
+    fn game_at(game: &TicTacGame, last_active: u64) -> (u32, u32) {
+        let user = game.new_user();
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+        game.data.lock().unwrap().board.get_mut(&id).unwrap().last_active = last_active;
+        (user, id)
+    }
+
+    #[test]
+    fn reap_removes_idle_games() {
+        let game = tictac(100);
+        let old = now_millis() - 2 * 60 * 1000;
+        let (user, idle) = game_at(&game, old);
+        let (_, other_idle) = game_at(&game, 0);
+        let (_, active) = game_at(&game, now_millis());
+        // The users were given out just now, so are kept this time
+        assert_eq!(game.reap(), (2, 0));
+        let guard = game.data.lock().unwrap();
+        assert!(!guard.board.contains_key(&idle));
+        assert!(!guard.board.contains_key(&other_idle));
+        assert!(guard.board.contains_key(&active));
+        assert_eq!(guard.current.get(&user), None);
+        assert_eq!(game.expired.load(Ordering::SeqCst), 2);
+        assert_eq!(game.evicted.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn reap_forgets_old_users_without_games() {
+        let game = tictac(100);
+        let fresh = game.new_user();
+        let idle = game.new_user();
+        let (player, _) = game_at(&game, now_millis());
+        let (guest_of, id) = game_at(&game, now_millis());
+        let guest = game.new_user();
+        {
+            let mut guard = game.data.lock().unwrap();
+            guard.board.get_mut(&id).unwrap().guest = Some(guest);
+            for user in &[idle, player, guest_of, guest] {
+                guard.users.insert(*user, 0);
+            }
+        }
+        assert_eq!(game.reap(), (0, 1));
+        let guard = game.data.lock().unwrap();
+        let mut left: Vec<u32> = guard.users.keys().cloned().collect();
+        left.sort();
+        let mut expected = vec![fresh, player, guest_of, guest];
+        expected.sort();
+        assert_eq!(left, expected);
+    }
+
+    #[test]
+    fn make_room_evicts_least_recently_active() {
+        let game = tictac(3);
+        let (_, second) = game_at(&game, 200);
+        let (_, first) = game_at(&game, 100);
+        let (_, third) = game_at(&game, 300);
+        let (_, newest) = game_at(&game, now_millis());
+        {
+            let guard = game.data.lock().unwrap();
+            assert_eq!(guard.board.len(), 3);
+            assert!(!guard.board.contains_key(&first));
+            assert!(guard.board.contains_key(&second));
+        }
+        assert_eq!(game.evicted.load(Ordering::SeqCst), 1);
+        game_at(&game, now_millis());
+        {
+            let guard = game.data.lock().unwrap();
+            assert_eq!(guard.board.len(), 3);
+            assert!(!guard.board.contains_key(&second));
+            assert!(guard.board.contains_key(&third));
+            assert!(guard.board.contains_key(&newest));
+        }
+        assert_eq!(game.evicted.load(Ordering::SeqCst), 2);
+        assert_eq!(game.expired.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! A pool of connections from the web_server to the game_server
+//!
+//! Connections are kept open and reused between requests, since the
+//! protocol allows any number of requests per connection. At most `size`
+//! connections are open at once, a call that can't get one within the
+//! timeout fails with `ClientError::Unavailable` so the caller can send a
+//! 503 rather than have threads pile up waiting on a slow game server.
+//!
+//! example:
+//!    let client = GameClient::new("localhost:3001", 8, Duration::from_secs(2));
+//!    let replies = client.call(&[GameRequest::GetGame { user_id: 1, game_id: None }]);
+//!
+
+use common::protocol::{self, GameRequest, GameResponse};
+use std::env;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Idle connections older than this are closed rather than reused, it is
+/// under the game_server's own 5 second read timeout
+const MAX_IDLE_SECS: u64 = 4;
+/// The first wait after a failed connect, doubled on each failure after
+const BACKOFF_START_MILLIS: u64 = 100;
+const BACKOFF_MAX_MILLIS: u64 = 5000;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// No connection could be had in time, or the game_server is down
+    /// and still in its backoff period
+    Unavailable(String),
+    /// The connection failed part way through a call
+    Io(String),
+}
+
+struct Idle {
+    stream: TcpStream,
+    since : Instant,
+}
+
+/// Everything behind the mutex
+struct State {
+    idle   : Vec<Idle>,
+    /// Connections open, idle or in use
+    open   : usize,
+    /// Connects are refused until this passes
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+pub struct GameClient {
+    addr     : String,
+    size     : usize,
+    timeout  : Duration,
+    state    : Mutex<State>,
+    /// Signalled whenever a connection is returned or closed
+    available: Condvar,
+}
+impl GameClient {
+    /// A pool of up to `size` connections to `addr`, `timeout` applies to
+    /// waiting for a connection, connecting, and each read and write
+    pub fn new(addr: &str, size: usize, timeout: Duration) -> GameClient {
+        GameClient {
+            addr: addr.to_string(),
+            size,
+            timeout,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+                retry_at: None,
+                backoff: Duration::from_millis(BACKOFF_START_MILLIS),
+            }),
+            available: Condvar::new(),
+        }
+    }
+    /// Size the pool from `GAME_POOL_SIZE` (default 8) and the timeout from
+    /// `GAME_TIMEOUT_MS` (default 2000)
+    pub fn from_env(addr: &str) -> GameClient {
+        let var = |name: &str, default: u64| match env::var(name) {
+            Ok(val) => val.parse().unwrap_or(default),
+            Err(_) => default,
+        };
+        GameClient::new(addr,
+                        var("GAME_POOL_SIZE", 8) as usize,
+                        Duration::from_millis(var("GAME_TIMEOUT_MS", 2000)))
+    }
+    /// Send each request and read its reply, all over one pooled connection
+    pub fn call(&self, requests: &[GameRequest]) -> Result<Vec<GameResponse>, ClientError> {
+        let mut stream = self.checkout()?;
+        let mut replies = Vec::new();
+        for request in requests {
+            if let Err(e) = protocol::write_frame(&mut stream, request) {
+                self.discard();
+                return Err(ClientError::Io(format!("Could not write to {:?}: {:?}", self.addr, e)));
+            }
+            match protocol::read_frame(&mut stream) {
+                Ok(Some(reply)) => replies.push(reply),
+                Ok(None) => {
+                    self.discard();
+                    return Err(ClientError::Io(format!("Stream to {:?} ended prematurely?", self.addr)));
+                }
+                Err(e) => {
+                    // Includes timeouts, the connection may still get a late reply
+                    // so it can't be reused
+                    self.discard();
+                    return Err(ClientError::Io(format!("Could not read from {:?}: {:?}", self.addr, e)));
+                }
+            }
+        }
+        self.checkin(stream);
+        Ok(replies)
+    }
+
+    /// Take a healthy idle connection, or open a new one if under `size`,
+    /// waiting up to the timeout for one to be returned otherwise
+    fn checkout(&self) -> Result<TcpStream, ClientError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while let Some(idle) = state.idle.pop() {
+                if is_healthy(&idle) {
+                    return Ok(idle.stream);
+                }
+                state.open -= 1;
+            }
+            if state.open < self.size {
+                if let Some(retry_at) = state.retry_at {
+                    if Instant::now() < retry_at {
+                        return Err(ClientError::Unavailable(format!("{:?} is down, retrying soon", self.addr)));
+                    }
+                }
+                state.open += 1;
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ClientError::Unavailable(format!("No connection to {:?} free", self.addr)));
+            }
+            state = self.available.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        // Connect without holding the lock, the slot is already counted in `open`
+        drop(state);
+        match self.connect() {
+            Ok(stream) => {
+                let mut state = self.state.lock().unwrap();
+                state.retry_at = None;
+                state.backoff = Duration::from_millis(BACKOFF_START_MILLIS);
+                Ok(stream)
+            }
+            Err(e) => {
+                let mut state = self.state.lock().unwrap();
+                state.open -= 1;
+                state.retry_at = Some(Instant::now() + state.backoff);
+                state.backoff = ::std::cmp::min(state.backoff * 2,
+                                                Duration::from_millis(BACKOFF_MAX_MILLIS));
+                self.available.notify_one();
+                Err(ClientError::Unavailable(format!("Game server down? {:?}", e)))
+            }
+        }
+    }
+    fn connect(&self) -> ::std::io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = self.addr.to_socket_addrs()?.collect();
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| ::std::io::Error::new(ErrorKind::NotFound, "no address")))
+    }
+    fn checkin(&self, stream: TcpStream) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push(Idle { stream, since: Instant::now() });
+        self.available.notify_one();
+    }
+    /// A checked out connection was dropped, free up its slot
+    fn discard(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        self.available.notify_one();
+    }
+}
+
+/// An idle connection is only reused if it is young enough not to have
+/// been timed out by the game_server, and hasn't been closed by it
+fn is_healthy(idle: &Idle) -> bool {
+    if idle.since.elapsed() >= Duration::from_secs(MAX_IDLE_SECS) {
+        return false;
+    }
+    // Nothing should be waiting to be read, a closed connection reads 0
+    if idle.stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 1];
+    let healthy = matches!(idle.stream.peek(&mut buf), Err(ref e) if e.kind() == ErrorKind::WouldBlock);
+    healthy && idle.stream.set_nonblocking(false).is_ok()
+}
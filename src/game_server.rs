@@ -1,208 +1,806 @@
 extern crate rustc_serialize;
 extern crate common;
 
-use common::UserData;
-use rustc_serialize::json;
-use std::io::{Read, Write};
+mod ai;
+mod expiry;
+mod store;
+
+use common::{Grid, GameStatus, Difficulty, Opponent, ThreadPool, BoardSize, Cell, EMPTY};
+use common::protocol::{self, GameRequest, GameResponse, GameInfo, GameError, ErrorCode, Move, Stats};
+use common::{crypto, shutdown};
+use std::process;
 use std::sync::{Arc, Mutex}; // for safely threading
-use std::thread::spawn; // spawning threads
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::time::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use expiry::Limits;
+use store::{Change, GameStore};
 
-/// A small note on the use of .unwrap() in this source
-/// It /should/ be changed to something to handle either None/Err
-/// safely as is done in the web_server,
-/// but for the purposes of the assignment, it works well enough to
-/// demonstrate.
+// A small note on the use of .unwrap() in this source
+// It /should/ be changed to something to handle either None/Err
+// safely as is done in the web_server,
+// but for the purposes of the assignment, it works well enough to
+// demonstrate.
 
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:3001").unwrap();
 
-    let tictac_data = Arc::new(TicTacGame::new());
+    // Games are kept by the store GAME_STORE names (see store.rs), and any
+    // it has kept from before are played on from where they were left
+    let tictac_data = match store::from_env().and_then(|store| TicTacGame::open(store, Limits::from_env())) {
+        Ok(game) => Arc::new(game),
+        Err(e) => {
+            println!("Could not open the game store: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut pool = ThreadPool::from_env();
+
+    // SIGINT or SIGTERM now end the loop below instead of killing the process
+    shutdown::install();
+    // Games no one touches for a while are removed, see expiry.rs
+    let reaper = expiry::spawn_reaper(tictac_data.clone());
 
     // This for loop operates the same as in web_server
-    for stream in listener.incoming().by_ref() {
+    for stream in shutdown::incoming(&listener).unwrap() {
         match stream {
             Ok(mut stream) => {
                 let fives = Duration::from_secs(5);
                 stream.set_read_timeout(Some(fives)).expect("set_read_timeout call failed");
                 stream.set_write_timeout(Some(fives)).expect("set_write_timeout call failed");
                 stream.set_ttl(100).expect("set_ttl call failed");
+                if pool.is_full() {
+                    write_error(&mut stream, GameError::new(ErrorCode::Busy, "Server busy"));
+                    continue;
+                }
                 // closure that calls a func to operate on the stream
                 let tictac_child = tictac_data.clone();
-                spawn(move || { handle_client(&mut stream, tictac_child); });
+                if let Err(e) = pool.execute(move || { handle_client(&mut stream, tictac_child); }) {
+                    println!("Could not queue connection: {:?}", e);
+                }
             }
             Err(e) => println!("Bad connection: {:?}", e),
         }
     }
+
+    // No new connections are accepted now, finish the moves in progress
+    // before the boards are saved
+    println!("Shutting down, waiting on open connections");
+    let drained = pool.shutdown_timeout(Duration::from_secs(SHUTDOWN_DEADLINE_SECS));
+    let _ = reaper.join();
+    if let Err(e) = tictac_data.flush() {
+        println!("{}", e);
+        process::exit(1);
+    }
+    if !drained {
+        println!("Connections still open after {}s, exiting anyway", SHUTDOWN_DEADLINE_SECS);
+        process::exit(1);
+    }
 }
 
+/// How long open connections get to finish once a shutdown is requested
+const SHUTDOWN_DEADLINE_SECS: u64 = 10;
+
 /// Game logic deals with this, and users board is parsed to JSON for sending
-#[derive(RustcEncodable)]
+#[derive(RustcEncodable, RustcDecodable)]
 struct TicTacBoard {
-    board: HashMap<u32, Vec<Vec<char>>>,
+    /// Every game by its id
+    board  : HashMap<u32, Game>,
+    /// Every id given out by `new_user` and when, in milliseconds since the
+    /// Unix epoch. An id with no game is forgotten after the TTL.
+    users  : HashMap<u32, u64>,
+    /// The game each user started last, played when no game id is given
+    current: HashMap<u32, u32>,
+}
+
+/// A single game, who is playing it and how hard the computer plays in it
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+struct Game {
+    /// The user playing X, who started the game
+    owner     : u32,
+    /// The user playing O, None against the computer or until someone joins
+    guest     : Option<u32>,
+    opponent  : Opponent,
+    grid      : Grid,
+    difficulty: Difficulty,
+    /// Goes up by one with every change to the game, starting at 0
+    seq       : u32,
+    /// Every move played, in order
+    moves     : Vec<Move>,
+    /// When the game was last played or looked at, in milliseconds since
+    /// the Unix epoch, games left too long are removed (see expiry.rs)
+    last_active: u64,
+}
+impl Game {
+    /// The piece `user_id` plays, None if they aren't in this game
+    fn seat(&self, user_id: u32) -> Option<char> {
+        if self.owner == user_id {
+            Some('X')
+        } else if self.guest == Some(user_id) {
+            Some('O')
+        } else {
+            None
+        }
+    }
+    /// The piece to play next, X always goes first
+    fn turn(&self) -> Option<char> {
+        if self.grid.status != GameStatus::InProgress {
+            return None;
+        }
+        let count = |piece| self.grid.data.iter().flat_map(|row| row.iter()).filter(|&&c| c == piece).count();
+        if count('X') > count('O') { Some('O') } else { Some('X') }
+    }
+}
+
+impl TicTacBoard {
+    fn new() -> TicTacBoard {
+        TicTacBoard {
+            board  : HashMap::new(),
+            users  : HashMap::new(),
+            current: HashMap::new(),
+        }
+    }
 }
 
 /// Create this struct containing the TicTacBoard struct within a mutex lock
+/// and the store every change to it is saved to
 struct TicTacGame {
-    data: Mutex<TicTacBoard>,
+    data   : Mutex<TicTacBoard>,
+    store  : Mutex<Box<dyn GameStore>>,
+    limits : Limits,
+    /// Games removed for being idle longer than the TTL
+    expired: AtomicUsize,
+    /// Games removed to keep under MAX_GAMES
+    evicted: AtomicUsize,
 }
 impl TicTacGame {
-    fn new() -> TicTacGame {
-        TicTacGame { data: Mutex::new(TicTacBoard { board: HashMap::new() }) }
-    }
-    /// Inserts a new blank game for user_id
-    fn new_game(&self, user_id: u32) {
-        // move % columns = col (x)
-        // move / columns = row (y)
-        let array = vec![vec!['0', '1', '2'], // row 0, x=0,1,2
-                         vec!['3', '4', '5'], // row 1
-                         vec!['6', '7', '8']]; // row 2
+    /// Start from the games `store` has kept
+    fn open(mut store: Box<dyn GameStore>, limits: Limits) -> Result<TicTacGame, String> {
+        let board = store.recover()?;
+        Ok(TicTacGame {
+            data   : Mutex::new(board),
+            store  : Mutex::new(store),
+            limits,
+            expired: AtomicUsize::new(0),
+            evicted: AtomicUsize::new(0),
+        })
+    }
+    /// Save a change just made to the board. It is called with the board
+    /// still locked, so the store gets changes in the order they were made.
+    /// The board in memory is the one played from, so a change the store
+    /// can't save is only logged.
+    fn record(&self, board: &TicTacBoard, change: Change) {
+        if let Err(e) = self.store.lock().unwrap().record(&change, board) {
+            println!("Could not save change: {}", e);
+        }
+    }
+    /// Save every game, on shutdown
+    fn flush(&self) -> Result<(), String> {
+        let guard = self.data.lock().unwrap(); // critical section begins
+        self.store.lock().unwrap().flush(&guard)
+    }
+    /// Allocate a random user id that hasn't been given out before, so
+    /// ids can't be guessed from each other
+    fn new_user(&self) -> u32 {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        let id = random_id(|id| guard.users.contains_key(&id));
+        let now = now_millis();
+        guard.users.insert(id, now);
+        self.record(&guard, Change::User(id, now));
+        id
+    }
+    /// The id of a game `user_id` may play, their current game if `game_id`
+    /// is None. Asking for a game counts as activity, keeping it from expiring.
+    fn resolve(&self, user_id: u32, game_id: Option<u32>) -> Result<u32, GameError> {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        let id = match game_id {
+            Some(id) => id,
+            None => match guard.current.get(&user_id) {
+                Some(&id) => id,
+                None => return Err(GameError::new(ErrorCode::NoSuchGame,
+                                                  &format!("User {:?} has no game", user_id))),
+            },
+        };
+        match guard.board.get_mut(&id) {
+            Some(ref mut game) if game.seat(user_id).is_some() => {
+                game.last_active = now_millis();
+                Ok(id)
+            }
+            _ => Err(no_such_game(id)),
+        }
+    }
+    /// Inserts a new blank game of `size` for user_id, which becomes their
+    /// current game, and returns its id. A game against a human waits for
+    /// someone to `join` before any moves can be made.
+    fn new_game(&self, user_id: u32, difficulty: Difficulty, opponent: Opponent, size: BoardSize)
+                -> Result<u32, GameError> {
+        if let Err(e) = size.check() {
+            return Err(GameError::new(ErrorCode::InvalidBoard, &e));
+        }
+        let array = blank_board(size.width, size.height);
+        let status = match opponent {
+            Opponent::Computer => GameStatus::InProgress,
+            Opponent::Human => GameStatus::Waiting,
+        };
+        let game = Game {
+            owner: user_id,
+            guest: None,
+            opponent,
+            grid: Grid { data: array, status, win: size.win },
+            difficulty,
+            seq: 0,
+            moves: Vec::new(),
+            last_active: now_millis(),
+        };
         // To safely read and write from the Mutex it must be locked
         let mut guard = self.data.lock().unwrap(); // critical section begins
-        guard.board.insert(user_id, array); // guard is dropped automatically at end of scope
-    } // The scope ends here
-    
-    /// A simple helper function to give us the JSON string without problems
-    fn get_json(&self, user_id: u32) -> Result<String, String> {
+        self.make_room(&mut guard);
+        let id = random_id(|id| guard.board.contains_key(&id));
+        // The board is changed before the change is recorded, as the store
+        // may write all of the board as a snapshot while recording it
+        guard.board.insert(id, game.clone());
+        self.record(&guard, Change::Game(id, game));
+        guard.current.insert(user_id, id);
+        self.record(&guard, Change::Current(user_id, id));
+        Ok(id)
+    } // The scope ends here, guard is dropped automatically
+
+    /// Take the O seat of a game waiting for a second player, which then
+    /// becomes the users current game. Joining a game already joined is
+    /// allowed, so a repeated request does no harm.
+    fn join(&self, game_id: u32, user_id: u32) -> Result<(), GameError> {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        {
+            let game = match guard.board.get_mut(&game_id) {
+                Some(x) => x,
+                None => return Err(no_such_game(game_id)),
+            };
+            if game.guest == Some(user_id) {
+                return Ok(());
+            }
+            if game.opponent != Opponent::Human {
+                return Err(GameError::new(ErrorCode::NotJoinable, "Game is against the computer"));
+            }
+            if game.owner == user_id {
+                return Err(GameError::new(ErrorCode::NotJoinable, "You can't join your own game"));
+            }
+            if game.guest.is_some() {
+                return Err(GameError::new(ErrorCode::NotJoinable, "Game already has two players"));
+            }
+            // Resigned before anyone joined
+            if game.grid.status != GameStatus::Waiting {
+                return Err(GameError::new(ErrorCode::NotJoinable, "Game is over"));
+            }
+            game.guest = Some(user_id);
+            game.grid.status = GameStatus::InProgress;
+            game.seq += 1;
+            game.last_active = now_millis();
+        }
+        guard.current.insert(user_id, game_id);
+        self.record(&guard, Change::Game(game_id, guard.board[&game_id].clone()));
+        self.record(&guard, Change::Current(user_id, game_id));
+        Ok(())
+    }
+
+    /// A copy of the game, for sending back
+    fn info(&self, game_id: u32) -> Result<GameInfo, GameError> {
         // Critical section begins
         let guard = self.data.lock().unwrap();
-        let data = guard.board.get(&user_id).unwrap().to_vec();
-        match json::encode(&data) {
-            Err(e) => return Err(format!("JSON conversion failed: {:?}", e)),
-            Ok(o) => Ok(o),
+        match guard.board.get(&game_id) {
+            Some(game) => Ok(GameInfo {
+                id        : game_id,
+                difficulty: game.difficulty,
+                opponent  : game.opponent,
+                owner     : game.owner,
+                guest     : game.guest,
+                turn      : game.turn(),
+                seq       : game.seq,
+                grid      : game.grid.clone(),
+            }),
+            None => Err(no_such_game(game_id)),
         }
     }
-    fn insert_move(&self, user_id: u32, place: char, piece: char) -> Result<bool, String> {
+    /// Play `user_id`s piece at `cell`, only on their turn
+    fn insert_move(&self, game_id: u32, user_id: u32, cell: Cell) -> Result<bool, GameError> {
         let mut guard = self.data.lock().unwrap(); // critical section begins
-        let mut board = match guard.board.get_mut(&user_id) {
+        let game = match guard.board.get_mut(&game_id) {
             Some(x) => x,
-            None => return Err(format!("Game for user {:?} does not exist", user_id)),
+            None => return Err(no_such_game(game_id)),
         };
-        let p = match place.to_string().parse::<u32>() {
-            Ok(o) => o,
-            Err(_) => return Err(String::from("Invalid input")),
+        let piece = match game.seat(user_id) {
+            Some(piece) => piece,
+            None => return Err(no_such_game(game_id)),
         };
-        if p > 8 {
-            return Err(String::from("Illegal move"));
-        }
-        let x = p as i32 % 3;
-        let y = p as i32 / 3;
-        let pos = board[y as usize][x as usize];
-        if pos == place {
-            board[y as usize][x as usize] = piece;
+        if game.grid.status.is_over() {
+            return Err(GameError::new(ErrorCode::GameOver, "Game is over"));
+        }
+        if game.grid.status == GameStatus::Waiting {
+            return Err(GameError::new(ErrorCode::NotYourTurn, "Waiting for a second player to join"));
+        }
+        if game.turn() != Some(piece) {
+            return Err(GameError::new(ErrorCode::NotYourTurn, "It is not your turn"));
+        }
+        let placed = place_piece(game, cell, piece, Some(user_id))?;
+        let change = Change::Game(game_id, game.clone());
+        self.record(&guard, change);
+        Ok(placed)
+    }
+    /// Let the computer pick and insert its move, at the difficulty the game
+    /// was started with. Nothing is done if the game is over, isn't against
+    /// the computer, or it isn't the computer's turn.
+    fn cpu_move(&self, game_id: u32, piece: char) -> Result<bool, GameError> {
+        loop {
+            // The board is copied so the search runs without the lock held,
+            // other games shouldn't wait on it
+            let (grid, difficulty, seq) = {
+                let guard = self.data.lock().unwrap(); // critical section begins
+                let game = match guard.board.get(&game_id) {
+                    Some(x) => x,
+                    None => return Err(no_such_game(game_id)),
+                };
+                if game.opponent != Opponent::Computer || game.turn() != Some(piece) {
+                    return Ok(false);
+                }
+                (game.grid.clone(), game.difficulty, game.seq)
+            }; // critical section ends
+            let place = match ai::choose_move(&grid, piece, difficulty) {
+                Some((row, col)) => Cell::At(row as u32, col as u32),
+                None => return Ok(false),
+            };
+            let mut guard = self.data.lock().unwrap(); // critical section begins
+            let change = match guard.board.get_mut(&game_id) {
+                // Changed while searching, eg by an undo, so search again
+                Some(game) if game.seq != seq => continue,
+                Some(game) if game.turn() == Some(piece) => {
+                    place_piece(game, place, piece, None)?;
+                    Change::Game(game_id, game.clone())
+                }
+                Some(_) => return Ok(false),
+                None => return Err(no_such_game(game_id)),
+            };
+            self.record(&guard, change);
             return Ok(true);
-        } else {
-            return Err(String::from("Illegal move"));
         }
     }
+    /// Take back the user's last move, and the computer's reply to it. The
+    /// board is played again from the start without them.
+    fn undo(&self, game_id: u32, user_id: u32) -> Result<(), GameError> {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        let game = match guard.board.get_mut(&game_id) {
+            Some(x) => x,
+            None => return Err(no_such_game(game_id)),
+        };
+        if game.seat(user_id).is_none() {
+            return Err(no_such_game(game_id));
+        }
+        if game.opponent != Opponent::Computer {
+            return Err(GameError::new(ErrorCode::CannotUndo, "Only moves against the computer can be taken back"));
+        }
+        if game.grid.status.is_over() {
+            return Err(GameError::new(ErrorCode::GameOver, "Game is over"));
+        }
+        // The computer replies straight away, so only its move can follow the user's
+        let last = match game.moves.iter().rposition(|m| m.player == Some(user_id)) {
+            Some(i) => i,
+            None => return Err(GameError::new(ErrorCode::CannotUndo, "There are no moves to take back")),
+        };
+        game.moves.truncate(last);
+        let width = game.grid.width();
+        game.grid.data = blank_board(width, game.grid.height());
+        for m in game.moves.iter() {
+            game.grid.data[(m.cell / width) as usize][(m.cell % width) as usize] = m.piece;
+        }
+        game.grid.status = check_status(&game.grid.data, game.grid.win as usize);
+        game.seq += 1;
+        let change = Change::Game(game_id, game.clone());
+        self.record(&guard, change);
+        Ok(())
+    }
+    /// Every move played in the game, oldest first
+    fn moves(&self, game_id: u32) -> Result<Vec<Move>, GameError> {
+        let guard = self.data.lock().unwrap(); // critical section begins
+        match guard.board.get(&game_id) {
+            Some(game) => Ok(game.moves.clone()),
+            None => Err(no_such_game(game_id)),
+        }
+    }
+    /// The user gives up, the other player (or the computer) wins
+    fn resign(&self, game_id: u32, user_id: u32) -> Result<(), GameError> {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        let game = match guard.board.get_mut(&game_id) {
+            Some(x) => x,
+            None => return Err(no_such_game(game_id)),
+        };
+        if game.grid.status.is_over() {
+            return Err(GameError::new(ErrorCode::GameOver, "Game is over"));
+        }
+        game.grid.status = match game.seat(user_id) {
+            Some('X') => GameStatus::OWon,
+            Some(_) => GameStatus::XWon,
+            None => return Err(no_such_game(game_id)),
+        };
+        game.seq += 1;
+        let change = Change::Game(game_id, game.clone());
+        self.record(&guard, change);
+        Ok(())
+    }
+    /// How many games and users there are, and how many games have been
+    /// removed by expiry.rs since start up
+    fn stats(&self) -> Stats {
+        let guard = self.data.lock().unwrap(); // critical section begins
+        Stats {
+            games  : guard.board.len() as u64,
+            users  : guard.users.len() as u64,
+            expired: self.expired.load(Ordering::SeqCst) as u64,
+            evicted: self.evicted.load(Ordering::SeqCst) as u64,
+        }
+    }
+    /// Delete the game, it is no longer anyone's current game
+    fn abandon(&self, game_id: u32) -> Result<(), GameError> {
+        let mut guard = self.data.lock().unwrap(); // critical section begins
+        if !guard.board.contains_key(&game_id) {
+            return Err(no_such_game(game_id));
+        }
+        guard.apply(Change::Remove(game_id));
+        self.record(&guard, Change::Remove(game_id));
+        Ok(())
+    }
 }
 
-fn write_error(stream: &mut TcpStream, msg: String) {
-    println!("{:?}", msg); // to console
-    // The use of `.unwrap_or()` here is a suitable substitute for a match
-    // statement or other types of result handling as we only care about
-    // whether the operation was successful or not.
-    stream
-        .write_all(msg.as_bytes())
-        .unwrap_or(println!("Failed to write to stream")); // to webserver
-    stream
-        .shutdown(Shutdown::Write)
-        .unwrap_or(println!("Stream closed before write?"));
+/// A `width` by `height` board with no moves
+fn blank_board(width: u32, height: u32) -> Vec<Vec<char>> {
+    vec![vec![EMPTY; width as usize]; height as usize]
 }
 
-/// Take stream and convert from JSON, perform logic, send JSON back
-/// A new game can be started by receiving;
-/// {"user_id":"number", "move_to":"-1", "new_game":true }
-///
-/// This block is too long for my liking, it really should be pared
-/// down to more general functions.
-///
-fn handle_client(stream: &mut TcpStream, game: Arc<TicTacGame>) {
-    // Read the incoming stream in to a buffer for working with
-    // TODO read to buffer and save length of read - Do it differennt
-    let mut buffer = String::new();
-    for byte in Read::by_ref(stream).bytes() {
-        buffer.push(byte.unwrap() as char);
+/// Put `piece` on `cell` if it is free, update the status and add the move
+/// to the game's history. `player` is None for the computer.
+fn place_piece(game: &mut Game, cell: Cell, piece: char, player: Option<u32>) -> Result<bool, GameError> {
+    let grid = &mut game.grid;
+    let (width, height) = (grid.width(), grid.height());
+    // cell % width = col (x)
+    // cell / width = row (y)
+    let (y, x) = match cell.position(width, height) {
+        Some(o) => o,
+        None => return Err(GameError::new(ErrorCode::IllegalMove, "Illegal move, the cell is off the board")),
+    };
+    if grid.data[y][x] != EMPTY {
+        return Err(GameError::new(ErrorCode::IllegalMove, "Illegal move, the cell is taken"));
     }
+    grid.data[y][x] = piece;
+    grid.status = check_status(&grid.data, grid.win as usize);
+    game.seq += 1;
+    let index = y as u32 * width + x as u32;
+    game.moves.push(Move { piece, player, cell: index, time: now_millis() });
+    Ok(true)
+}
 
-    let split: Vec<&str> = buffer.splitn(2, ':').collect();
-    if split.len() < 2 {
-        write_error(stream, format!("Invalid JSON received"));
-        return;
+/// The time now, in milliseconds since the Unix epoch
+fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0,
     }
-    let code = split[0].parse::<u32>().unwrap();
-    let buffer = split[1];
+}
 
-    // decode the buffer from JSON to the UserData struct
-    let user_data: UserData;
-    match json::decode(&buffer) {
-        Err(e) => {
-            write_error(stream, format!("Invalid JSON received: {:?}", e));
-            return;
+fn no_such_game(game_id: u32) -> GameError {
+    GameError::new(ErrorCode::NoSuchGame, &format!("Game {:?} does not exist", game_id))
+}
+
+/// A random non-zero id for which `taken` is false
+fn random_id<F: Fn(u32) -> bool>(taken: F) -> u32 {
+    loop {
+        let bytes = crypto::random_bytes(4);
+        let id = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+                 (bytes[2] as u32) << 8 | bytes[3] as u32;
+        if id != 0 && !taken(id) {
+            return id;
         }
-        Ok(o) => user_data = o,
     }
+}
 
-    if user_data.new_game {
-        game.new_game(user_data.user_id)
+/// Evaluate every row, column and diagonal of a board for `win` of the
+/// same piece in a row. A board with no line and no free cells left is a draw.
+fn check_status(board: &[Vec<char>], win: usize) -> GameStatus {
+    let height = board.len() as isize;
+    let width = board.first().map_or(0, |row| row.len()) as isize;
+    // Across, down and both diagonals, as (row, col) steps
+    let directions = [(0, 1), (1, 0), (1, 1), (1, -1)];
+    for y in 0..height {
+        for x in 0..width {
+            let first = board[y as usize][x as usize];
+            if first == EMPTY {
+                continue;
+            }
+            // Each line is found from its first cell, so only look forward
+            for &(dy, dx) in directions.iter() {
+                let (end_y, end_x) = (y + dy * (win as isize - 1), x + dx * (win as isize - 1));
+                if end_y >= height || end_x < 0 || end_x >= width {
+                    continue;
+                }
+                if (1..win as isize).all(|i| board[(y + dy * i) as usize][(x + dx * i) as usize] == first) {
+                    match first {
+                        'X' => return GameStatus::XWon,
+                        'O' => return GameStatus::OWon,
+                        _ => {}
+                    }
+                }
+            }
+        }
     }
+    let full = board.iter().all(|row| row.iter().all(|&c| c != EMPTY));
+    if full { GameStatus::Draw } else { GameStatus::InProgress }
+}
 
-    // Return just the JSON without making a move - move can be anything
-    if code == 1 {
-        match game.get_json(user_data.user_id) {
+/// Send an error frame, used when the request can't be handled at all
+fn write_error(stream: &mut TcpStream, error: GameError) {
+    println!("{:?}", error); // to console
+    if protocol::write_frame(stream, &GameResponse::Error(error)).is_err() {
+        println!("Failed to write to stream"); // to webserver
+    }
+}
+
+/// Read request frames from the stream and answer each in turn, until the
+/// web server closes the connection. See `common::protocol` for the format.
+///
+fn handle_client(stream: &mut TcpStream, game: Arc<TicTacGame>) {
+    loop {
+        let request: GameRequest = match protocol::read_frame(stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return, // closed by the web server
             Err(e) => {
-                let msg = format!("User {:?}: {:?}", user_data.user_id, e);
-                write_error(stream, msg);
-                return;
-            }
-            Ok(o) => {
-                stream.write_all(o.as_bytes()).unwrap();
-                stream.shutdown(Shutdown::Write).unwrap();
+                // The stream can't be trusted past a bad frame, so answer and close
+                write_error(stream, e.to_game_error());
                 return;
             }
+        };
+        println!("Request = {:?}", request);
+        let response = match handle_request(&game, request) {
+            Ok(response) => response,
+            Err(e) => GameResponse::Error(e),
+        };
+        if let Err(e) = protocol::write_frame(stream, &response) {
+            println!("Failed to write to stream: {:?}", e);
+            return;
         }
     }
+}
 
-    // Insert user move
-    match game.insert_move(user_data.user_id, user_data.move_to, 'X') {
-        Ok(_) => {}
-        Err(_) => {
-            // Return early if an error, write a valid JSON if possible
-            match game.get_json(user_data.user_id) {
-                Err(e) => {
-                    let msg = format!("User {:?}: {:?}", user_data.user_id, e);
-                    write_error(stream, msg);
-                    return;
-                }
-                Ok(o) => {
-                    stream.write_all(o.as_bytes()).unwrap();
-                    stream.shutdown(Shutdown::Write).unwrap();
-                    return;
-                }
+/// Carry out a single request, most requests reply with the game as it
+/// is afterwards
+fn handle_request(game: &TicTacGame, request: GameRequest) -> Result<GameResponse, GameError> {
+    let info = match request {
+        GameRequest::NewUser => return Ok(GameResponse::User(game.new_user())),
+        GameRequest::NewGame { user_id, difficulty, opponent, size } => {
+            let id = game.new_game(user_id, difficulty, opponent, size)?;
+            game.info(id)?
+        }
+        GameRequest::JoinGame { user_id, game_id } => {
+            game.join(game_id, user_id)?;
+            game.info(game_id)?
+        }
+        GameRequest::GetGame { user_id, game_id } => game.info(game.resolve(user_id, game_id)?)?,
+        GameRequest::MakeMove { user_id, game_id, cell } => {
+            let id = game.resolve(user_id, game_id)?;
+            // Insert user move, as X or O depending on their seat
+            game.insert_move(id, user_id, cell)?;
+            // Insert computer move, this does nothing in a game between two
+            // users or if the users move ended the game
+            game.cpu_move(id, 'O')?;
+            game.info(id)?
+        }
+        GameRequest::Undo { user_id, game_id } => {
+            let id = game.resolve(user_id, game_id)?;
+            game.undo(id, user_id)?;
+            game.info(id)?
+        }
+        GameRequest::GetMoves { user_id, game_id } => {
+            let id = game.resolve(user_id, game_id)?;
+            return Ok(GameResponse::Moves(id, game.moves(id)?));
+        }
+        GameRequest::GetStats => return Ok(GameResponse::Stats(game.stats())),
+        GameRequest::Resign { user_id, game_id } => {
+            let id = game.resolve(user_id, game_id)?;
+            game.resign(id, user_id)?;
+            game.info(id)?
+        }
+        GameRequest::Abandon { user_id, game_id } => {
+            let id = game.resolve(user_id, game_id)?;
+            game.abandon(id)?;
+            return Ok(GameResponse::Abandoned(id));
+        }
+    };
+    Ok(GameResponse::Game(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use store::{FileStore, MemoryStore};
+
+    fn tictac() -> TicTacGame {
+        let limits = Limits { ttl_ms: 60 * 1000, max_games: 100, interval: 60 };
+        TicTacGame::open(Box::new(MemoryStore), limits).unwrap()
+    }
+
+    #[test]
+    fn join_after_owner_resigned() {
+        let game = tictac();
+        let (owner, guest) = (game.new_user(), game.new_user());
+        let id = game.new_game(owner, Difficulty::Hard, Opponent::Human, BoardSize::classic()).unwrap();
+        game.resign(id, owner).unwrap();
+        let e = game.join(id, guest).unwrap_err();
+        assert_eq!(e.code, ErrorCode::NotJoinable);
+        let info = game.info(id).unwrap();
+        assert_eq!(info.guest, None);
+        assert_eq!(info.grid.status, GameStatus::OWon);
+    }
+
+    #[test]
+    fn join_waiting_game() {
+        let game = tictac();
+        let (owner, guest) = (game.new_user(), game.new_user());
+        let id = game.new_game(owner, Difficulty::Hard, Opponent::Human, BoardSize::classic()).unwrap();
+        game.join(id, guest).unwrap();
+        let info = game.info(id).unwrap();
+        assert_eq!(info.guest, Some(guest));
+        assert_eq!(info.grid.status, GameStatus::InProgress);
+        assert_eq!(game.join(id, game.new_user()).unwrap_err().code, ErrorCode::NotJoinable);
+    }
+
+    #[test]
+    fn new_game_survives_compaction() {
+        let dir = env::temp_dir().join(format!("tictac-compact-{}", process::id()));
+        let dir = dir.to_str().unwrap();
+        let limits = || Limits { ttl_ms: 60 * 1000, max_games: 100, interval: 60 };
+        // The user is the first change and the game the second, which
+        // writes the snapshot
+        let (user, id) = {
+            let store = FileStore::with_compact_after(dir, 2);
+            let game = TicTacGame::open(Box::new(store), limits()).unwrap();
+            let user = game.new_user();
+            let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+            (user, id)
+        };
+        let game = TicTacGame::open(Box::new(FileStore::new(dir)), limits()).unwrap();
+        assert_eq!(game.resolve(user, None).unwrap(), id);
+        assert_eq!(game.info(id).unwrap().owner, user);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn computer_replies_on_a_large_board() {
+        let game = tictac();
+        let user = game.new_user();
+        let size = BoardSize { width: 19, height: 19, win: 5 };
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, size).unwrap();
+        let request = GameRequest::MakeMove { user_id: user, game_id: Some(id), cell: Cell::At(9, 9) };
+        match handle_request(&game, request).unwrap() {
+            GameResponse::Game(info) => {
+                let pieces = info.grid.data.iter().flat_map(|row| row.iter()).filter(|&&c| c != EMPTY).count();
+                assert_eq!(pieces, 2);
+                assert_eq!(info.grid.data[9][9], 'X');
+                assert_eq!(info.turn, Some('X'));
             }
+            _ => panic!("Expected the game"),
         }
+        // Nothing happens when it isn't the computer's turn
+        assert!(!game.cpu_move(id, 'O').unwrap());
     }
-    // Insert computer move
-    for cpu in 0..8 {
-        let ch = format!("{}", cpu).as_bytes()[0] as char;
-        match game.insert_move(user_data.user_id, ch, 'O') {
-            Ok(_) => break,
-            Err(_) => {}
+
+    /// The board the moves give when played in order
+    fn replay(moves: &[Move], width: u32, height: u32) -> Vec<Vec<char>> {
+        let mut data = blank_board(width, height);
+        for m in moves {
+            data[(m.cell / width) as usize][(m.cell % width) as usize] = m.piece;
         }
+        data
     }
 
-    println!("JSON = {:?}", game.get_json(user_data.user_id).unwrap());
-    match game.get_json(user_data.user_id) {
-        Err(e) => {
-            let msg = format!("User {:?}: {:?}", user_data.user_id, e);
-            write_error(stream, msg);
-            return;
+    #[test]
+    fn undo_takes_back_the_move_and_reply() {
+        let game = tictac();
+        let user = game.new_user();
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+        handle_request(&game, GameRequest::MakeMove { user_id: user, game_id: None, cell: Cell::Index(4) }).unwrap();
+        let before = game.info(id).unwrap();
+        let moves = game.moves(id).unwrap();
+        assert_eq!(moves.len(), 2);
+        let free = (0..9).find(|&i| before.grid.data[i / 3][i % 3] == EMPTY).unwrap() as u32;
+        handle_request(&game, GameRequest::MakeMove { user_id: user, game_id: None, cell: Cell::Index(free) }).unwrap();
+        assert_eq!(game.moves(id).unwrap().len(), 4);
+
+        match handle_request(&game, GameRequest::Undo { user_id: user, game_id: None }).unwrap() {
+            GameResponse::Game(info) => {
+                assert_eq!(info.grid.data, before.grid.data);
+                assert_eq!(info.turn, Some('X'));
+                assert!(info.seq > before.seq);
+            }
+            _ => panic!("Expected the game"),
         }
-        Ok(o) => {
-            stream.write_all(o.as_bytes()).unwrap();
-            stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(game.moves(id).unwrap(), moves);
+        // And back to the start
+        game.undo(id, user).unwrap();
+        assert!(game.moves(id).unwrap().is_empty());
+        assert_eq!(game.info(id).unwrap().grid.data, blank_board(3, 3));
+    }
+
+    #[test]
+    fn undo_refuses_without_a_move() {
+        let game = tictac();
+        let (user, other) = (game.new_user(), game.new_user());
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+        assert_eq!(game.undo(id, user).unwrap_err().code, ErrorCode::CannotUndo);
+        // Only a player can take a move back
+        game.insert_move(id, user, Cell::Index(0)).unwrap();
+        assert_eq!(game.undo(id, other).unwrap_err().code, ErrorCode::NoSuchGame);
+        assert_eq!(game.moves(id).unwrap().len(), 1);
+
+        // Nor in a game between two users
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Human, BoardSize::classic()).unwrap();
+        game.join(id, other).unwrap();
+        game.insert_move(id, user, Cell::Index(0)).unwrap();
+        assert_eq!(game.undo(id, user).unwrap_err().code, ErrorCode::CannotUndo);
+    }
+
+    #[test]
+    fn undo_refuses_once_the_game_is_over() {
+        let game = tictac();
+        let user = game.new_user();
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+        // Play the first free cell until the game ends
+        while !game.info(id).unwrap().grid.status.is_over() {
+            let data = game.info(id).unwrap().grid.data;
+            let free = (0..9).find(|&i| data[i / 3][i % 3] == EMPTY).unwrap() as u32;
+            handle_request(&game, GameRequest::MakeMove { user_id: user, game_id: Some(id), cell: Cell::Index(free) })
+                .unwrap();
+        }
+        let moves = game.moves(id).unwrap();
+        assert_eq!(game.undo(id, user).unwrap_err().code, ErrorCode::GameOver);
+        assert_eq!(game.moves(id).unwrap(), moves);
+
+        let id = game.new_game(user, Difficulty::Hard, Opponent::Computer, BoardSize::classic()).unwrap();
+        handle_request(&game, GameRequest::MakeMove { user_id: user, game_id: Some(id), cell: Cell::Index(4) }).unwrap();
+        game.resign(id, user).unwrap();
+        assert_eq!(game.undo(id, user).unwrap_err().code, ErrorCode::GameOver);
+    }
+
+    #[test]
+    fn moves_match_the_board() {
+        let game = tictac();
+        let user = game.new_user();
+        let size = BoardSize { width: 5, height: 4, win: 4 };
+        let id = game.new_game(user, Difficulty::Medium, Opponent::Computer, size).unwrap();
+        let check = || {
+            let moves = match handle_request(&game, GameRequest::GetMoves { user_id: user, game_id: None }).unwrap() {
+                GameResponse::Moves(moves_id, moves) => {
+                    assert_eq!(moves_id, id);
+                    moves
+                }
+                _ => panic!("Expected the moves"),
+            };
+            assert_eq!(replay(&moves, 5, 4), game.info(id).unwrap().grid.data);
+            // The user and the computer take turns, X first
+            for (i, m) in moves.iter().enumerate() {
+                let (piece, player) = if i % 2 == 0 { ('X', Some(user)) } else { ('O', None) };
+                assert_eq!((m.piece, m.player), (piece, player));
+            }
+            moves.len()
+        };
+        for &cell in &[Cell::At(1, 1), Cell::At(2, 3), Cell::At(3, 0)] {
+            let data = game.info(id).unwrap().grid.data;
+            let cell = match cell {
+                Cell::At(row, col) if data[row as usize][col as usize] == EMPTY => cell,
+                _ => Cell::Index((0..20).find(|&i| data[i / 5][i % 5] == EMPTY).unwrap() as u32),
+            };
+            game.insert_move(id, user, cell).unwrap();
+            game.cpu_move(id, 'O').unwrap();
+            check();
         }
+        assert_eq!(check(), 6);
+        game.undo(id, user).unwrap();
+        assert_eq!(check(), 4);
     }
 }
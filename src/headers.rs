@@ -0,0 +1,186 @@
+//! An ordered, multi-valued map of HTTP headers
+//!
+//! Names are matched ignoring case, and a name can hold several values
+//! (eg one `Set-Cookie` per cookie), kept in the order they were added.
+//! Names must be tokens and values must not hold CR, LF or other control
+//! characters, so nothing added here can split or inject a header.
+//!
+//! example:
+//!    let mut headers = Headers::new();
+//!    headers.insert("Content-Type", "text/html").unwrap();
+//!    headers.append("Set-Cookie", "a=1").unwrap();
+//!    headers.append("Set-Cookie", "b=2").unwrap();
+//!    assert_eq!(headers.get("content-type").unwrap(), "text/html");
+//!    assert_eq!(headers.get_all("set-cookie").len(), 2);
+//!
+
+use std::fmt;
+use std::slice::Iter;
+use is_token;
+
+/// Why a header could not be added
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    /// The name is empty or not a token
+    InvalidName(String),
+    /// The value holds CR, LF or another control character
+    InvalidValue(String),
+}
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::InvalidName(ref name) => write!(f, "Invalid header name {:?}", name),
+            HeaderError::InvalidValue(ref val) => write!(f, "Invalid header value {:?}", val),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+    /// The first value for `name`
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val)
+    }
+    /// Every value for `name`, in the order they were added
+    pub fn get_all(&self, name: &str) -> Vec<&String> {
+        self.entries.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val)
+            .collect()
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+    /// Set `name` to a single value, replacing any it had. The header keeps
+    /// the place of its first value.
+    pub fn insert(&mut self, name: &str, val: &str) -> Result<(), HeaderError> {
+        check(name, val)?;
+        match self.entries.iter().position(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some(i) => {
+                self.entries[i] = (name.to_string(), val.to_string());
+                let mut seen = 0;
+                self.entries.retain(|(key, _)| {
+                    if !key.eq_ignore_ascii_case(name) {
+                        return true;
+                    }
+                    seen += 1;
+                    seen == 1
+                });
+            }
+            None => self.entries.push((name.to_string(), val.to_string())),
+        }
+        Ok(())
+    }
+    /// Add another value for `name`, after any it already has
+    pub fn append(&mut self, name: &str, val: &str) -> Result<(), HeaderError> {
+        check(name, val)?;
+        self.entries.push((name.to_string(), val.to_string()));
+        Ok(())
+    }
+    /// Remove every value for `name`, returning whether there were any
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.entries.len() != len
+    }
+    /// Every (name, value) in order, a name with several values is
+    /// returned once for each
+    pub fn iter<'a>(&'a self) -> Iter<'a, (String, String)> {
+        self.entries.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Check a header can be written without breaking the message, values may
+/// hold tabs and non-ascii text but no other control characters
+fn check(name: &str, val: &str) -> Result<(), HeaderError> {
+    if !is_token(name) {
+        return Err(HeaderError::InvalidName(name.to_string()));
+    }
+    if val.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(HeaderError::InvalidValue(val.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(headers: &Headers) -> Vec<(&str, &str)> {
+        headers.iter().map(|(key, val)| (key.as_str(), val.as_str())).collect()
+    }
+
+    #[test]
+    fn names_ignore_case() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/html").unwrap();
+        assert_eq!(headers.get("content-type").unwrap(), "text/html");
+        assert_eq!(headers.get("CONTENT-TYPE").unwrap(), "text/html");
+        assert!(headers.contains("content-TYPE"));
+        assert!(!headers.contains("Content-Length"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn several_values_keep_their_order() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1").unwrap();
+        headers.append("Vary", "Cookie").unwrap();
+        headers.append("set-cookie", "b=2").unwrap();
+        assert_eq!(headers.get("Set-Cookie").unwrap(), "a=1");
+        assert_eq!(headers.get_all("SET-COOKIE"), vec!["a=1", "b=2"]);
+        assert_eq!(pairs(&headers), vec![("Set-Cookie", "a=1"), ("Vary", "Cookie"), ("set-cookie", "b=2")]);
+        assert_eq!(headers.len(), 3);
+        assert!(headers.get_all("Location").is_empty());
+    }
+
+    #[test]
+    fn insert_replaces_and_append_adds() {
+        let mut headers = Headers::new();
+        headers.append("Cache-Control", "no-cache").unwrap();
+        headers.append("Vary", "Cookie").unwrap();
+        headers.append("cache-control", "no-store").unwrap();
+        // Every value goes, the new one takes the place of the first
+        headers.insert("CACHE-CONTROL", "max-age=60").unwrap();
+        assert_eq!(pairs(&headers), vec![("CACHE-CONTROL", "max-age=60"), ("Vary", "Cookie")]);
+        headers.append("Cache-Control", "public").unwrap();
+        assert_eq!(headers.get_all("cache-control"), vec!["max-age=60", "public"]);
+
+        assert!(headers.remove("cache-control"));
+        assert!(!headers.remove("cache-control"));
+        assert_eq!(pairs(&headers), vec![("Vary", "Cookie")]);
+        assert!(headers.remove("Vary"));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        let mut headers = Headers::new();
+        for name in &["", "Bad Name", "X-Split\r\nInjected", "X-Colon:", "X-Nul\0"] {
+            assert_eq!(headers.insert(name, "ok"), Err(HeaderError::InvalidName(name.to_string())));
+            assert_eq!(headers.append(name, "ok"), Err(HeaderError::InvalidName(name.to_string())));
+        }
+        for val in &["a\r\nSet-Cookie: x=1", "a\rb", "a\nb", "a\0b", "a\x7fb"] {
+            assert_eq!(headers.insert("X-Test", val), Err(HeaderError::InvalidValue(val.to_string())));
+            assert_eq!(headers.append("X-Test", val), Err(HeaderError::InvalidValue(val.to_string())));
+        }
+        assert!(headers.is_empty());
+        // Tabs and non-ascii text are fine in values
+        headers.insert("X-Test", "a\tb é").unwrap();
+        assert_eq!(headers.get("x-test").unwrap(), "a\tb é");
+    }
+}
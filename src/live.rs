@@ -0,0 +1,602 @@
+//! Live updates for the players of a game
+//!
+//! A game page opens a WebSocket to `/game/:id/ws`, and every time the
+//! game changes (a move, the second player joining) the new state is sent
+//! to every socket open on that game as a JSON text message:
+//! `{"id": 1, "seq": 3, "status": "InProgress", "turn": "O", "board": [["X","1","2"], ..]}`.
+//! The page compares it with what it shows and reloads when it differs.
+//! `seq` is the game's sequence number, which goes up with every change.
+//!
+//! Each socket gets its own thread rather than a worker from the pool, as
+//! it stays open for as long as the page does. WS_MAX_SOCKETS (default 64)
+//! limits how many can be open at once.
+//!
+//! Clients that can't use WebSockets can get the same updates as
+//! server-sent events from `/game/:id/events`, each with the game's `seq`
+//! as its id so a reconnecting `EventSource` resumes where it left off.
+//! An event stream holds a pool worker while it is open, so
+//! EVENTS_MAX_STREAMS (default 4) of them are allowed at once, and each is
+//! ended after `EVENTS_STREAM_SECS` for the client to reconnect.
+//!
+//! The game_server doesn't tell the web_server when it removes an idle
+//! game. A player's request for a game answered with a 404 is taken as
+//! the sign, and closes everything subscribed to the game.
+//!
+
+use common::{Request, Response, Status, GameStatus, Slot, Slots, shutdown};
+use common::protocol::GameInfo;
+use common::websocket::{self, Message, MessageReader, WsError};
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufReader, Read};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use {Context, require_session, game_id_param, get_game};
+
+/// How often an idle socket is pinged, so dead connections are noticed
+/// and proxies don't time it out
+const PING_INTERVAL_SECS: u64 = 30;
+/// Nothing is expected from the browser but pongs, so messages are small
+const MAX_MESSAGE: usize = 4096;
+/// How long an event stream stays open before the client has to reconnect
+const EVENTS_STREAM_SECS: u64 = 300;
+/// How often a comment is sent on an idle event stream, so a client that
+/// has gone is noticed when it can't be written to
+const EVENTS_KEEPALIVE_SECS: u64 = 15;
+/// How long a reconnecting `EventSource` waits, in milliseconds
+const EVENTS_RETRY_MS: u32 = 2000;
+
+/// The state of a game as it is pushed to the sockets, the same for both
+/// players
+#[derive(RustcEncodable)]
+struct LiveGame<'a> {
+    id    : u32,
+    seq   : u32,
+    status: GameStatus,
+    turn  : Option<char>,
+    board : &'a Vec<Vec<char>>,
+}
+
+/// One state of a game, encoded once for every subscriber
+pub struct Update {
+    pub seq : u32,
+    pub json: String,
+}
+
+/// One socket or stream listening to a game
+struct Subscriber {
+    id     : usize,
+    user_id: u32,
+    /// The game_server has shown `user_id` the game, so they are a player
+    player : bool,
+    tx     : Sender<Arc<Update>>,
+}
+
+/// The subscribers of each game
+type Games = Arc<Mutex<HashMap<u32, Vec<Subscriber>>>>;
+
+/// Who is listening for changes to which game
+pub struct Live {
+    games  : Games,
+    next_id: AtomicUsize,
+    sockets: Slots,
+    streams: Slots,
+}
+impl Live {
+    pub fn new(max_sockets: usize, max_streams: usize) -> Live {
+        Live {
+            games: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicUsize::new(0),
+            sockets: Slots::new(max_sockets),
+            streams: Slots::new(max_streams),
+        }
+    }
+    /// Allow `WS_MAX_SOCKETS` (default 64) sockets and `EVENTS_MAX_STREAMS`
+    /// (default 4) event streams at once
+    pub fn from_env() -> Live {
+        let sockets = env::var("WS_MAX_SOCKETS").ok().and_then(|v| v.parse().ok()).unwrap_or(64);
+        let streams = env::var("EVENTS_MAX_STREAMS").ok().and_then(|v| v.parse().ok()).unwrap_or(4);
+        Live::new(sockets, streams)
+    }
+
+    /// Receive every later state of `game_id` for `user_id`, until the
+    /// game is abandoned or the `Subscription` is dropped. States can
+    /// arrive twice or out of order, anything with a `seq` no higher than
+    /// the last one used should be skipped. The user isn't taken to be a
+    /// player until the subscription is `confirm`ed.
+    pub fn subscribe(&self, game_id: u32, user_id: u32) -> Subscription {
+        let (tx, rx) = mpsc::channel();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let subscriber = Subscriber { id, user_id, player: false, tx };
+        self.games.lock().unwrap().entry(game_id).or_default().push(subscriber);
+        Subscription { updates: rx, game_id, id, games: self.games.clone() }
+    }
+
+    /// The game_server has given the subscriber the game, so they are one
+    /// of its players
+    pub fn confirm(&self, subscription: &Subscription) {
+        let mut games = self.games.lock().unwrap(); // critical section begins
+        if let Some(subscribers) = games.get_mut(&subscription.game_id) {
+            for sub in subscribers.iter_mut().filter(|sub| sub.id == subscription.id) {
+                sub.player = true;
+            }
+        }
+    }
+
+    /// Send the state of a game to everyone subscribed to it. Subscribers
+    /// that have gone away are dropped here.
+    pub fn publish(&self, game: &GameInfo) {
+        let update = match encode(game) {
+            Some(update) => Arc::new(update),
+            None => return,
+        };
+        let mut games = self.games.lock().unwrap(); // critical section begins
+        let empty = match games.get_mut(&game.id) {
+            Some(subscribers) => {
+                subscribers.retain(|sub| sub.tx.send(update.clone()).is_ok());
+                subscribers.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            games.remove(&game.id);
+        }
+    }
+
+    /// The game is gone, close everything subscribed to it
+    pub fn close(&self, game_id: u32) {
+        self.games.lock().unwrap().remove(&game_id);
+    }
+
+    /// Close the subscribers of `game_id` if `response`, the answer to a
+    /// request for it by `user_id`, is a 404 and `user_id` has a confirmed
+    /// subscription to it. The game_server has then removed the game. A 404
+    /// to anyone else means nothing, it is also what a game that isn't
+    /// theirs gets.
+    pub fn check_removed(&self, game_id: u32, user_id: u32, response: &Response) {
+        if response.status_code() != 404 {
+            return;
+        }
+        let mut games = self.games.lock().unwrap(); // critical section begins
+        let subscribed = match games.get(&game_id) {
+            Some(subscribers) => subscribers.iter().any(|sub| sub.player && sub.user_id == user_id),
+            None => false,
+        };
+        if subscribed {
+            println!("Game {} is gone, closing its subscribers", game_id);
+            games.remove(&game_id);
+        }
+    }
+}
+
+/// The updates to one game, its subscriber is removed when this is dropped
+pub struct Subscription {
+    updates: Receiver<Arc<Update>>,
+    game_id: u32,
+    id     : usize,
+    games  : Games,
+}
+impl Subscription {
+    /// The next update, `Disconnected` once the game is closed
+    fn recv_timeout(&self, timeout: Duration) -> Result<Arc<Update>, RecvTimeoutError> {
+        self.updates.recv_timeout(timeout)
+    }
+}
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut games = self.games.lock().unwrap(); // critical section begins
+        let empty = match games.get_mut(&self.game_id) {
+            Some(subscribers) => {
+                subscribers.retain(|sub| sub.id != self.id);
+                subscribers.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            games.remove(&self.game_id);
+        }
+    }
+}
+
+fn encode(game: &GameInfo) -> Option<Update> {
+    let live = LiveGame {
+        id    : game.id,
+        seq   : game.seq,
+        status: game.grid.status,
+        turn  : game.turn,
+        board : &game.grid.data,
+    };
+    match json::encode(&live) {
+        Ok(o) => Some(Update { seq: game.seq, json: o }),
+        Err(e) => {
+            println!("Could not encode game {}: {:?}", game.id, e);
+            None
+        }
+    }
+}
+
+/// Open a WebSocket for a game via url '/game/:id/ws', for either of its
+/// players. The current state is sent straight away, then every change.
+///
+pub fn handle_socket(request: &Request, ctx: &Context) -> Response {
+    let user_id = match require_session(request, ctx) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    // Browsers send the session cookie with a socket opened by any site,
+    // so only pages from this server may open one
+    if !same_origin(request) {
+        return Status::forbidden();
+    }
+    let game_id = match game_id_param(request) {
+        Ok(Some(id)) => id,
+        Ok(None) | Err(_) => return Status::not_found(),
+    };
+    let mut response = match websocket::handshake(request) {
+        Ok(response) => response,
+        Err(e) => return e,
+    };
+    let slot = match ctx.live.sockets.reserve() {
+        Some(slot) => slot,
+        None => return too_many(),
+    };
+    // Subscribed before the game is fetched so no change can be missed
+    // in between
+    let updates = ctx.live.subscribe(game_id, user_id);
+    let first = match get_game(user_id, Some(game_id), &ctx.client) {
+        Ok(game) => match encode(&game) {
+            Some(first) => first,
+            None => return Status::internal_error(),
+        },
+        Err(e) => {
+            // This request's own subscription proves nothing, it may be
+            // for a game that isn't theirs
+            drop(updates);
+            ctx.live.check_removed(game_id, user_id, &e);
+            return e;
+        }
+    };
+    ctx.live.confirm(&updates);
+    response.upgrade(move |stream| {
+        thread::spawn(move || serve_socket(stream, first, updates, slot));
+    });
+    response
+}
+
+/// Stream a game's changes as server-sent events via url
+/// '/game/:id/events', for either of its players. Each event's id is the
+/// game's `seq`. A client sending `Last-Event-ID` gets the current state
+/// only if it has changed since that event, otherwise it is sent first.
+///
+pub fn handle_events(request: &Request, ctx: &Context) -> Response {
+    let user_id = match require_session(request, ctx) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    let game_id = match game_id_param(request) {
+        Ok(Some(id)) => id,
+        Ok(None) | Err(_) => return Status::not_found(),
+    };
+    let slot = match ctx.live.streams.reserve() {
+        Some(slot) => slot,
+        None => return too_many(),
+    };
+    let last_seq = request.get_header("Last-Event-ID").and_then(|id| id.trim().parse::<u32>().ok());
+    // As for sockets, subscribe first so nothing is missed
+    let updates = ctx.live.subscribe(game_id, user_id);
+    let current = match get_game(user_id, Some(game_id), &ctx.client) {
+        Ok(game) => match encode(&game) {
+            Some(current) => current,
+            None => return Status::internal_error(),
+        },
+        Err(e) => {
+            // As for sockets, this request's own subscription proves nothing
+            drop(updates);
+            ctx.live.check_removed(game_id, user_id, &e);
+            return e;
+        }
+    };
+    ctx.live.confirm(&updates);
+
+    let mut events = EventStream::new(updates, last_seq, slot);
+    events.push(&current);
+    let mut response = Status::ok();
+    response.header("Content-Type", "text/event-stream");
+    response.header("Cache-Control", "no-cache");
+    // Tells nginx and similar proxies not to hold the events back
+    response.header("X-Accel-Buffering", "no");
+    response.stream(events, None);
+    response
+}
+
+/// Too many sockets or streams are open, the client should try later
+fn too_many() -> Response {
+    let mut response = Status::service_unavailable();
+    response.header("Retry-After", "5");
+    response
+}
+
+/// The body of an event stream, each read blocks until there is an event
+/// (or a keep alive comment) to send. It ends when the game is abandoned,
+/// after `EVENTS_STREAM_SECS`, or when the server is shutting down.
+struct EventStream {
+    updates : Subscription,
+    /// The seq of the last event sent, or the client's Last-Event-ID
+    last_seq: Option<u32>,
+    /// Bytes not yet read, and how many of them have been
+    pending : Vec<u8>,
+    sent    : usize,
+    started : Instant,
+    /// How long since anything was sent
+    idle    : Duration,
+    _slot   : Slot,
+}
+impl EventStream {
+    /// A stream resuming after `last_seq`, the client's Last-Event-ID
+    fn new(updates: Subscription, last_seq: Option<u32>, slot: Slot) -> EventStream {
+        EventStream {
+            updates,
+            last_seq,
+            pending : format!("retry: {}\n\n", EVENTS_RETRY_MS).into_bytes(),
+            sent    : 0,
+            started : Instant::now(),
+            idle    : Duration::from_secs(0),
+            _slot   : slot,
+        }
+    }
+    /// Queue `update` as an event, unless the client already has it
+    fn push(&mut self, update: &Update) {
+        if self.last_seq.is_some_and(|last| update.seq <= last) {
+            return;
+        }
+        self.last_seq = Some(update.seq);
+        // The JSON has no newlines, so it fits on one data line
+        let event = format!("id: {}\ndata: {}\n\n", update.seq, update.json);
+        self.pending.extend_from_slice(event.as_bytes());
+    }
+}
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Waiting is done a second at a time to notice a shutdown
+        let tick = Duration::from_secs(1);
+        while self.sent == self.pending.len() {
+            self.pending.clear();
+            self.sent = 0;
+            if shutdown::requested() || self.started.elapsed() >= Duration::from_secs(EVENTS_STREAM_SECS) {
+                return Ok(0);
+            }
+            match self.updates.recv_timeout(tick) {
+                Ok(update) => {
+                    self.push(&update);
+                    self.idle = Duration::from_secs(0);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.idle += tick;
+                    if self.idle >= Duration::from_secs(EVENTS_KEEPALIVE_SECS) {
+                        self.pending.extend_from_slice(b": keep-alive\n\n");
+                        self.idle = Duration::from_secs(0);
+                    }
+                }
+                // The game was abandoned
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.sent);
+        buf[..n].copy_from_slice(&self.pending[self.sent..self.sent + n]);
+        self.sent += n;
+        Ok(n)
+    }
+}
+
+/// Whether the Origin, if the browser sent one, is this server
+fn same_origin(request: &Request) -> bool {
+    let origin = match request.get_header("Origin") {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let host = match origin.find("://") {
+        Some(i) => &origin[i + 3..],
+        None => return false,
+    };
+    match request.get_header("Host") {
+        Some(expected) => host.eq_ignore_ascii_case(expected),
+        None => false,
+    }
+}
+
+/// Write `first` and then each update to the socket until either side
+/// closes it. Messages from the browser are read on a second thread, which
+/// answers pings and closes.
+fn serve_socket(stream: TcpStream, first: Update, updates: Subscription, _slot: Slot) {
+    let ping = Duration::from_secs(PING_INTERVAL_SECS);
+    // A browser answers every ping, so a socket silent for two has gone
+    if stream.set_read_timeout(Some(ping * 2)).is_err() ||
+       stream.set_write_timeout(Some(ping)).is_err() {
+        println!("Could not set socket timeouts");
+        return;
+    }
+    let read_half = match stream.try_clone() {
+        Ok(read_half) => read_half,
+        Err(e) => {
+            println!("Could not clone socket: {:?}", e);
+            return;
+        }
+    };
+    let writer = Arc::new(Mutex::new(stream));
+    let reply = writer.clone();
+    thread::spawn(move || read_socket(read_half, &reply));
+
+    let mut last_seq = first.seq;
+    let mut message = Message::Text(first.json);
+    loop {
+        if send(&writer, &message).is_err() {
+            break;
+        }
+        if let Message::Close(_) = message {
+            break;
+        }
+        message = next_message(&updates, &mut last_seq, ping);
+    }
+    shutdown(&writer);
+}
+
+/// Wait for the next state newer than `last_seq` to send, or a ping if
+/// there is none within `ping`
+fn next_message(updates: &Subscription, last_seq: &mut u32, ping: Duration) -> Message {
+    loop {
+        match updates.recv_timeout(ping) {
+            // An older or repeated state is left out
+            Ok(ref update) if update.seq <= *last_seq => {}
+            Ok(update) => {
+                *last_seq = update.seq;
+                return Message::Text(update.json.clone());
+            }
+            Err(RecvTimeoutError::Timeout) => return Message::Ping(Vec::new()),
+            // The game was abandoned
+            Err(RecvTimeoutError::Disconnected) => return Message::Close(Some(1001)),
+        }
+    }
+}
+
+/// Handle what the browser sends, until it closes the socket or breaks
+/// the protocol
+fn read_socket(stream: TcpStream, writer: &Mutex<TcpStream>) {
+    let mut reader = MessageReader::new(BufReader::new(stream), MAX_MESSAGE);
+    loop {
+        match reader.read_message() {
+            Ok(Message::Ping(data)) => {
+                if send(writer, &Message::Pong(data)).is_err() {
+                    break;
+                }
+            }
+            // Echoing the close completes the closing handshake
+            Ok(Message::Close(_)) => {
+                let _ = send(writer, &Message::Close(Some(1000)));
+                break;
+            }
+            // Nothing else sent by the page is used
+            Ok(_) => {}
+            Err(WsError::Io(_)) => break,
+            Err(e) => {
+                println!("Closing socket: {:?}", e);
+                let _ = send(writer, &Message::Close(Some(e.close_code())));
+                break;
+            }
+        }
+    }
+    // The writing thread finds out when its next write fails
+    shutdown(writer);
+}
+
+fn send(writer: &Mutex<TcpStream>, message: &Message) -> ::std::io::Result<()> {
+    let mut stream = writer.lock().unwrap();
+    websocket::write_message(&mut *stream, message)
+}
+
+fn shutdown(writer: &Mutex<TcpStream>) {
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribed(live: &Live, game_id: u32) -> usize {
+        live.games.lock().unwrap().get(&game_id).map(|subs| subs.len()).unwrap_or(0)
+    }
+
+    #[test]
+    fn dropped_subscriptions_are_removed() {
+        let live = Live::new(1, 1);
+        let first = live.subscribe(7, 1);
+        let second = live.subscribe(7, 2);
+        assert_eq!(subscribed(&live, 7), 2);
+        drop(first);
+        assert_eq!(subscribed(&live, 7), 1);
+        drop(second);
+        assert!(live.games.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn removed_games_close_for_players_only() {
+        let live = Live::new(1, 1);
+        let updates = live.subscribe(7, 1);
+        live.confirm(&updates);
+        // Someone else's 404 is no sign the game is gone
+        live.check_removed(7, 2, &Status::not_found());
+        live.check_removed(7, 1, &Status::service_unavailable());
+        assert_eq!(subscribed(&live, 7), 1);
+        live.check_removed(7, 1, &Status::not_found());
+        assert_eq!(subscribed(&live, 7), 0);
+        match updates.recv_timeout(Duration::from_millis(10)) {
+            Err(RecvTimeoutError::Disconnected) => {}
+            other => panic!("{:?}", other.map(|u| u.seq)),
+        }
+        drop(updates);
+        assert!(live.games.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn non_players_cant_close_a_game() {
+        let live = Live::new(1, 1);
+        let owner = live.subscribe(7, 1);
+        let guest = live.subscribe(7, 2);
+        live.confirm(&owner);
+        live.confirm(&guest);
+        // A socket opened on someone else's game subscribes before its
+        // GetGame is answered with a 404
+        let stranger = live.subscribe(7, 3);
+        live.check_removed(7, 3, &Status::not_found());
+        assert_eq!(subscribed(&live, 7), 3);
+        drop(stranger);
+        live.check_removed(7, 3, &Status::not_found());
+        assert_eq!(subscribed(&live, 7), 2);
+        for player in &[owner, guest] {
+            match player.recv_timeout(Duration::from_millis(1)) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => panic!("A player was disconnected"),
+            }
+        }
+    }
+
+    fn update(seq: u32) -> Update {
+        Update { seq, json: format!("{{\"seq\":{}}}", seq) }
+    }
+
+    /// Everything the stream has to send without waiting
+    fn queued(events: &mut EventStream) -> String {
+        let text = String::from_utf8(events.pending[events.sent..].to_vec()).unwrap();
+        events.sent = events.pending.len();
+        text
+    }
+
+    #[test]
+    fn events_resume_after_last_event_id() {
+        let live = Live::new(1, 3);
+        // A client that already has seq 3 isn't sent it again
+        let mut events = EventStream::new(live.subscribe(7, 1), Some(3), live.streams.reserve().unwrap());
+        events.push(&update(3));
+        assert_eq!(queued(&mut events), "retry: 2000\n\n");
+        live.games.lock().unwrap().get(&7).unwrap()[0].tx.send(Arc::new(update(4))).unwrap();
+        let mut buf = [0u8; 64];
+        let n = events.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &b"id: 4\ndata: {\"seq\":4}\n\n"[..]);
+        // Older and repeated states are left out
+        events.push(&update(2));
+        events.push(&update(4));
+        assert_eq!(queued(&mut events), "");
+
+        // A new client, or one whose id is behind, gets the current state first
+        let mut events = EventStream::new(live.subscribe(7, 1), None, live.streams.reserve().unwrap());
+        events.push(&update(4));
+        assert_eq!(queued(&mut events), "retry: 2000\n\nid: 4\ndata: {\"seq\":4}\n\n");
+        let mut behind = EventStream::new(live.subscribe(7, 1), Some(1), live.streams.reserve().unwrap());
+        behind.push(&update(4));
+        assert!(queued(&mut behind).ends_with("id: 4\ndata: {\"seq\":4}\n\n"));
+    }
+}
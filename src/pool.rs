@@ -0,0 +1,182 @@
+//! A fixed size pool of worker threads, shared by both servers
+//!
+//! Jobs are queued on a channel and picked up by whichever worker is free.
+//! The queue is bounded, `is_full` lets the accept loop turn connections
+//! away (eg with a 503) instead of queueing without limit. `Slots` counts
+//! connections that hold on to a worker (or a thread of their own), so
+//! they can be kept from taking them all.
+//!
+//! example:
+//!    let pool = ThreadPool::new(4, 16);
+//!    pool.execute(move || handle_client(stream)).unwrap();
+//!
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Why a job could not be queued
+#[derive(Debug, PartialEq)]
+pub enum PoolError {
+    /// The queue already holds as many jobs as it was created with
+    Full,
+    /// `shutdown` has been called
+    ShutDown,
+}
+
+pub struct ThreadPool {
+    workers : Vec<JoinHandle<()>>,
+    sender  : Option<Sender<Job>>,
+    /// Jobs queued or running
+    pending : Arc<AtomicUsize>,
+    /// Most jobs allowed to be pending, the workers plus the queue
+    limit   : usize,
+}
+impl ThreadPool {
+    /// Start `size` workers, with room for `capacity` jobs waiting on them
+    pub fn new(size: usize, capacity: usize) -> ThreadPool {
+        assert!(size > 0, "a ThreadPool needs at least one worker");
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = receiver.clone();
+                let pending = pending.clone();
+                thread::spawn(move || worker(id, receiver, pending))
+            })
+            .collect();
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            pending,
+            limit: size + capacity,
+        }
+    }
+    /// Size the pool from the `POOL_SIZE` and `POOL_QUEUE` environment
+    /// variables, defaulting to 8 workers and a queue of 64
+    pub fn from_env() -> ThreadPool {
+        let var = |name: &str, default: usize| match env::var(name) {
+            Ok(val) => val.parse().unwrap_or(default),
+            Err(_) => default,
+        };
+        ThreadPool::new(var("POOL_SIZE", 8), var("POOL_QUEUE", 64))
+    }
+    /// The number of workers
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+    /// Whether a job queued now would be refused, every worker is busy
+    /// and the queue is full
+    pub fn is_full(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) >= self.limit
+    }
+    /// Queue a job for the next free worker
+    pub fn execute<F>(&self, job: F) -> Result<(), PoolError>
+        where F: FnOnce() + Send + 'static
+    {
+        let sender = match self.sender {
+            Some(ref sender) => sender,
+            None => return Err(PoolError::ShutDown),
+        };
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(PoolError::Full);
+        }
+        match sender.send(Box::new(job)) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                Err(PoolError::ShutDown)
+            }
+        }
+    }
+    /// Stop taking jobs, and wait up to `deadline` for the workers to finish
+    /// every job already queued or running. Returns false if jobs were still
+    /// pending at the deadline, the workers are then left to run detached.
+    pub fn shutdown_timeout(&mut self, deadline: Duration) -> bool {
+        self.sender = None;
+        let start = Instant::now();
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= deadline {
+                self.workers.clear();
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.shutdown();
+        true
+    }
+    /// Stop taking jobs, and wait for the workers to finish every job
+    /// already queued or running
+    pub fn shutdown(&mut self) {
+        // Dropping the sender ends the channel once it is drained
+        self.sender = None;
+        for handle in self.workers.drain(..) {
+            if handle.join().is_err() {
+                println!("A worker thread panicked during shutdown");
+            }
+        }
+    }
+}
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A count of open connections of one kind, with a limit
+pub struct Slots {
+    open: Arc<AtomicUsize>,
+    max : usize,
+}
+impl Slots {
+    pub fn new(max: usize) -> Slots {
+        Slots { open: Arc::new(AtomicUsize::new(0)), max }
+    }
+    /// Count a new connection, None if there are already `max` open. It is
+    /// counted until the `Slot` is dropped.
+    pub fn reserve(&self) -> Option<Slot> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot { open: self.open.clone() })
+    }
+}
+
+pub struct Slot {
+    open: Arc<AtomicUsize>,
+}
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The loop each worker runs, until the channel is closed and empty
+fn worker(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, pending: Arc<AtomicUsize>) {
+    loop {
+        // The lock is only held while waiting for a job, not while running it
+        let message = match receiver.lock() {
+            Ok(guard) => guard.recv(),
+            Err(_) => return,
+        };
+        let job = match message {
+            Ok(job) => job,
+            Err(_) => return, // sender dropped, pool is shutting down
+        };
+        // A panicking job shouldn't take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            println!("Worker {} recovered from a panicked job", id);
+        }
+        pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,358 @@
+//! The protocol spoken between the web_server and game_server
+//!
+//! Every message is sent as one frame:
+//!
+//! ```text
+//! +---------+------------------+-----------------+
+//! | version | length           | payload         |
+//! | 1 byte  | 4 bytes, big end | `length` bytes  |
+//! +---------+------------------+-----------------+
+//! ```
+//!
+//! `version` is `VERSION`, a frame with any other version is answered
+//! with an `UnsupportedVersion` error and the connection is closed. The
+//! payload is a JSON encoded `GameRequest` (client to server) or
+//! `GameResponse` (server to client), at most `MAX_PAYLOAD` bytes.
+//!
+//! Each request gets exactly one response, in the order the requests were
+//! sent, so a client can send any number of requests over one connection.
+//! The server closes its end once the client closes theirs.
+//!
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json;
+use std::io::{self, ErrorKind, Read, Write};
+use {BoardSize, Cell, Difficulty, Grid, Opponent};
+
+/// The protocol version written in every frame
+pub const VERSION: u8 = 8;
+/// Largest payload accepted, a board is far smaller than this
+pub const MAX_PAYLOAD: u32 = 64 * 1024;
+
+/// What the web_server can ask of the game_server
+///
+/// A `game_id` of None means the game the user started or joined last,
+/// which is how the html pages play. A game the user isn't playing in is
+/// answered as if it doesn't exist, except by `JoinGame`.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq)]
+pub enum GameRequest {
+    /// Allocate a user id no one else has, for a new visitor
+    NewUser,
+    /// Start a blank game of `size` for the user, who plays X, it becomes
+    /// their current game. Against a `Human` the game waits for someone to
+    /// join.
+    NewGame { user_id: u32, difficulty: Difficulty, opponent: Opponent, size: BoardSize },
+    /// Take the O seat of a game waiting for a second player
+    JoinGame { user_id: u32, game_id: u32 },
+    /// Fetch the game without changing it
+    GetGame { user_id: u32, game_id: Option<u32> },
+    /// Play the users move, only allowed on their turn. Against the
+    /// computer it replies in the same request.
+    MakeMove { user_id: u32, game_id: Option<u32>, cell: Cell },
+    /// Take back the user's last move and the computer's reply to it,
+    /// only in a game against the computer that isn't over
+    Undo { user_id: u32, game_id: Option<u32> },
+    /// Every move played in the game so far, in order
+    GetMoves { user_id: u32, game_id: Option<u32> },
+    /// The user gives up, the other player (or the computer) wins
+    Resign { user_id: u32, game_id: Option<u32> },
+    /// Delete the game
+    Abandon { user_id: u32, game_id: Option<u32> },
+    /// Counts of the games the server holds and has removed
+    GetStats,
+}
+
+/// The game_server's answer to a GameRequest
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub enum GameResponse {
+    /// The id allocated by `NewUser`
+    User(u32),
+    /// The game after the request was carried out
+    Game(GameInfo),
+    /// The moves asked for by `GetMoves`, with the game's id
+    Moves(u32, Vec<Move>),
+    /// The id of the game deleted by `Abandon`
+    Abandoned(u32),
+    /// The counts asked for by `GetStats`
+    Stats(Stats),
+    Error(GameError),
+}
+
+/// A game as sent to the web_server
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct GameInfo {
+    pub id        : u32,
+    pub difficulty: Difficulty,
+    pub opponent  : Opponent,
+    /// The user playing X, who started the game
+    pub owner     : u32,
+    /// The user playing O, None against the computer or until someone joins
+    pub guest     : Option<u32>,
+    /// The piece to play next, None while waiting or once the game is over
+    pub turn      : Option<char>,
+    /// Counts the changes made to the game (moves, a player joining or
+    /// resigning), so a copy can be told apart from an older one
+    pub seq       : u32,
+    pub grid      : Grid,
+}
+impl GameInfo {
+    /// The piece `user_id` plays, None if they aren't in this game
+    pub fn seat(&self, user_id: u32) -> Option<char> {
+        if self.owner == user_id {
+            Some('X')
+        } else if self.guest == Some(user_id) {
+            Some('O')
+        } else {
+            None
+        }
+    }
+}
+
+/// A move played in a game
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Move {
+    pub piece : char,
+    /// The user who played it, None for the computer
+    pub player: Option<u32>,
+    /// The cell it was played in, counting along each row from 0
+    pub cell  : u32,
+    /// When it was played, in milliseconds since the Unix epoch
+    pub time  : u64,
+}
+
+/// How many games the game_server holds, and how many it has removed to
+/// save memory since it started
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub games  : u64,
+    pub users  : u64,
+    /// Games removed after going untouched for longer than the TTL
+    pub expired: u64,
+    /// Games removed to make room for new ones, once the cap was reached
+    pub evicted: u64,
+}
+
+/// Why a request failed
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The frame or its payload couldn't be read
+    BadFrame,
+    /// The frame was sent with a version other than `VERSION`
+    UnsupportedVersion,
+    /// There is no game for the user
+    NoSuchGame,
+    /// The cell is taken or off the board
+    IllegalMove,
+    /// A new game's board is too big, too small or can't be won on
+    InvalidBoard,
+    /// The game has already been won or drawn
+    GameOver,
+    /// It is the other player's turn, or no one has joined yet
+    NotYourTurn,
+    /// The game can't be joined, it is against the computer or already full
+    NotJoinable,
+    /// There is no move to take back, or the game isn't against the computer
+    CannotUndo,
+    /// The server has too many connections to take this one
+    Busy,
+    Internal,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct GameError {
+    pub code   : ErrorCode,
+    pub message: String,
+}
+impl GameError {
+    pub fn new(code: ErrorCode, message: &str) -> GameError {
+        GameError { code, message: message.to_string() }
+    }
+}
+
+/// Problems reading or writing a frame
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The peer speaks a version other than `VERSION`
+    Version(u8),
+    /// The length is over `MAX_PAYLOAD`
+    TooLarge(u32),
+    /// The payload isn't valid JSON for the expected type
+    Json(String),
+}
+impl FrameError {
+    /// The error to answer a bad frame with
+    pub fn to_game_error(&self) -> GameError {
+        match *self {
+            FrameError::Version(v) => GameError::new(ErrorCode::UnsupportedVersion,
+                                                     &format!("Version {} is not supported, use {}", v, VERSION)),
+            ref e => GameError::new(ErrorCode::BadFrame, &format!("{:?}", e)),
+        }
+    }
+}
+
+/// Encode `message` as JSON and write it as a single frame
+pub fn write_frame<W: Write, T: Encodable>(stream: &mut W, message: &T) -> Result<(), FrameError> {
+    let payload = match json::encode(message) {
+        Ok(o) => o.into_bytes(),
+        Err(e) => return Err(FrameError::Json(format!("{:?}", e))),
+    };
+    if payload.len() > MAX_PAYLOAD as usize {
+        return Err(FrameError::TooLarge(payload.len() as u32));
+    }
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(VERSION);
+    frame.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    frame.extend_from_slice(&payload);
+    // One write for the whole frame so it isn't split in to tiny packets
+    stream.write_all(&frame).map_err(FrameError::Io)?;
+    stream.flush().map_err(FrameError::Io)
+}
+
+/// Read a single frame and decode its payload. Returns None if the stream
+/// was closed cleanly before the start of a frame.
+pub fn read_frame<R: Read, T: Decodable>(stream: &mut R) -> Result<Option<T>, FrameError> {
+    let mut header = [0u8; 5];
+    // The first byte is read alone to tell a closed stream from a cut off frame
+    match stream.read(&mut header[..1]) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(FrameError::Io(e)),
+    }
+    stream.read_exact(&mut header[1..]).map_err(FrameError::Io)?;
+    if header[0] != VERSION {
+        return Err(FrameError::Version(header[0]));
+    }
+    let len = (header[1] as u32) << 24 | (header[2] as u32) << 16 |
+              (header[3] as u32) << 8 | header[4] as u32;
+    if len > MAX_PAYLOAD {
+        return Err(FrameError::TooLarge(len));
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).map_err(FrameError::Io)?;
+    let text = match String::from_utf8(payload) {
+        Ok(text) => text,
+        Err(_) => return Err(FrameError::Json("Payload is not valid UTF-8".to_string())),
+    };
+    match json::decode(&text) {
+        Ok(message) => Ok(Some(message)),
+        Err(e) => Err(FrameError::Json(format!("{:?}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn frame(version: u8, payload: &[u8]) -> Vec<u8> {
+        let len = payload.len() as u32;
+        let mut frame = vec![version, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let requests = vec![
+            GameRequest::NewUser,
+            GameRequest::NewGame { user_id: 7, difficulty: Difficulty::Medium,
+                                   opponent: Opponent::Human, size: BoardSize::classic() },
+            GameRequest::MakeMove { user_id: 7, game_id: None, cell: Cell::At(2, 1) },
+            GameRequest::Undo { user_id: 7, game_id: Some(3) },
+            GameRequest::GetStats,
+        ];
+        let mut stream = Vec::new();
+        for request in &requests {
+            write_frame(&mut stream, request).unwrap();
+        }
+        assert_eq!(stream[0], VERSION);
+        assert_eq!(&stream[1..5], &[0, 0, 0, 9]);
+        assert_eq!(&stream[5..14], b"\"NewUser\"");
+
+        // Read back in the order written, then a clean end of stream
+        let mut stream = Cursor::new(stream);
+        for request in &requests {
+            let read: Option<GameRequest> = read_frame(&mut stream).unwrap();
+            assert_eq!(read.as_ref(), Some(request));
+        }
+        let read: Option<GameRequest> = read_frame(&mut stream).unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let moves = vec![Move { piece: 'X', player: Some(7), cell: 4, time: 1_500_000_000_000 },
+                         Move { piece: 'O', player: None, cell: 0, time: 1_500_000_000_001 }];
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &GameResponse::Moves(3, moves.clone())).unwrap();
+        write_frame(&mut stream, &GameResponse::Error(GameError::new(ErrorCode::NotYourTurn, "Wait"))).unwrap();
+        let mut stream = Cursor::new(stream);
+        match read_frame(&mut stream).unwrap() {
+            Some(GameResponse::Moves(3, read)) => assert_eq!(read, moves),
+            other => panic!("Expected the moves, got {:?}", other),
+        }
+        match read_frame(&mut stream).unwrap() {
+            Some(GameResponse::Error(e)) => {
+                assert_eq!(e.code, ErrorCode::NotYourTurn);
+                assert_eq!(e.message, "Wait");
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wrong_version_is_refused() {
+        let mut stream = Cursor::new(frame(VERSION - 1, b"\"NewUser\""));
+        match read_frame::<_, GameRequest>(&mut stream) {
+            Err(e @ FrameError::Version(_)) => {
+                assert_eq!(e.to_game_error().code, ErrorCode::UnsupportedVersion);
+            }
+            other => panic!("Expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversize_length_is_refused() {
+        // Refused on the length alone, before any payload is read
+        let mut header = frame(VERSION, b"");
+        header[1..5].copy_from_slice(&[0, 1, 0, 1]);
+        match read_frame::<_, GameRequest>(&mut Cursor::new(header)) {
+            Err(e @ FrameError::TooLarge(0x10001)) => {
+                assert_eq!(e.to_game_error().code, ErrorCode::BadFrame);
+            }
+            other => panic!("Expected a length error, got {:?}", other),
+        }
+        // And never written
+        let mut stream = Vec::new();
+        let big = "x".repeat(MAX_PAYLOAD as usize);
+        match write_frame(&mut stream, &big) {
+            Err(FrameError::TooLarge(_)) => assert!(stream.is_empty()),
+            other => panic!("Expected a length error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let whole = frame(VERSION, b"\"NewUser\"");
+        // Cut in the header, and in the payload
+        for &cut in &[3, whole.len() - 1] {
+            match read_frame::<_, GameRequest>(&mut Cursor::new(&whole[..cut])) {
+                Err(FrameError::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {}
+                other => panic!("Expected end of file after {} bytes, got {:?}", cut, other),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_payloads_are_errors() {
+        for payload in &[&b"{\"NoSuchRequest\":1}"[..], b"not json", b"\xff\xfe"] {
+            match read_frame::<_, GameRequest>(&mut Cursor::new(frame(VERSION, payload))) {
+                Err(FrameError::Json(_)) => {}
+                other => panic!("Expected a JSON error for {:?}, got {:?}", payload, other),
+            }
+        }
+    }
+}
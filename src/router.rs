@@ -0,0 +1,319 @@
+//! Routing of requests to handlers by method and path
+//!
+//! Patterns are split on '/', and each segment is either a literal,
+//! a `:name` param that matches any one segment, or a `*name` param
+//! that matches the rest of the path. Params are stored in
+//! `Request::path_params` before the handler is called.
+//!
+//! example:
+//!    let mut router = Router::new();
+//!    router.get("/game/:id", handle_game);
+//!    router.post("/game/:id/move", handle_move);
+//!    let response = router.dispatch(&mut request);
+//!
+
+use std::collections::HashMap;
+use {Request, Response, Status};
+
+/// Anything that can answer a Request. Handlers are shared between the
+/// threads serving connections, so they must be Send + Sync.
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Changes the router's own 404 and 405 responses, see `Router::errors`
+pub type ErrorHook = Box<dyn Fn(Response) -> Response + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Route {
+    method  : String,
+    segments: Vec<Segment>,
+    handler : Handler,
+}
+impl Route {
+    /// The params captured if this route's pattern matches `path`
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                Segment::Rest(ref name) => {
+                    params.insert(name.clone(), path[i..].join("/"));
+                    return Some(params);
+                }
+                _ if i >= path.len() => return None,
+                Segment::Literal(ref lit) => {
+                    if lit != path[i] {
+                        return None;
+                    }
+                }
+                Segment::Param(ref name) => {
+                    params.insert(name.clone(), path[i].to_string());
+                }
+            }
+        }
+        if self.segments.len() == path.len() { Some(params) } else { None }
+    }
+}
+
+/// A table of routes, checked in the order they were added
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Hooks for the errors of urls under each prefix
+    errors: Vec<(Vec<Segment>, ErrorHook)>,
+}
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new(), errors: Vec::new() }
+    }
+    /// Add a handler for `method` requests to urls matching `pattern`
+    pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F)
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.routes.push(Route {
+            method: method.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+    }
+    pub fn get<F>(&mut self, pattern: &str, handler: F)
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("GET", pattern, handler);
+    }
+    pub fn post<F>(&mut self, pattern: &str, handler: F)
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("POST", pattern, handler);
+    }
+    pub fn put<F>(&mut self, pattern: &str, handler: F)
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("PUT", pattern, handler);
+    }
+    pub fn delete<F>(&mut self, pattern: &str, handler: F)
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route("DELETE", pattern, handler);
+    }
+    /// Pass the 404 and 405 responses for urls under `prefix` through
+    /// `hook`, eg so an API answers in the same format as its handlers. The
+    /// hook of the longest matching prefix is used.
+    ///
+    /// example:
+    ///    api.errors("/", json_error); // once mounted, covers all of /api
+    ///
+    pub fn errors<F>(&mut self, prefix: &str, hook: F)
+        where F: Fn(Response) -> Response + Send + Sync + 'static
+    {
+        self.errors.push((parse_pattern(prefix), Box::new(hook)));
+    }
+    /// Move every route (and error hook) of `router` in to this one, under
+    /// `prefix`
+    ///
+    /// example:
+    ///    let mut api = Router::new();
+    ///    api.get("/games/:id", handle_get);
+    ///    router.mount("/api", api); // serves GET /api/games/:id
+    ///
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        let prefix = parse_pattern(prefix);
+        for mut route in router.routes {
+            let mut segments = prefix.clone();
+            segments.append(&mut route.segments);
+            route.segments = segments;
+            self.routes.push(route);
+        }
+        for (mut under, hook) in router.errors {
+            let mut segments = prefix.clone();
+            segments.append(&mut under);
+            self.errors.push((segments, hook));
+        }
+    }
+    /// Find the handler for a request and call it, storing any path params
+    /// in the request first. Gives a 404 if no pattern matches the url, or
+    /// a 405 with an `Allow` header if the pattern matches but the method
+    /// doesn't. A HEAD request is answered by the GET handler, without the
+    /// body, unless a HEAD route was added for it.
+    pub fn dispatch(&self, request: &mut Request) -> Response {
+        let mut response = self.find(request);
+        response.head = request.method == "HEAD";
+        response
+    }
+    fn find(&self, request: &mut Request) -> Response {
+        let url = request.url.clone();
+        let path: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+        let mut allowed: Vec<&str> = Vec::new();
+        // The GET route a HEAD request falls back to
+        let mut get = None;
+        for route in &self.routes {
+            if let Some(params) = route.matches(&path) {
+                if route.method == request.method {
+                    request.path_params = params;
+                    return (route.handler)(request);
+                }
+                if route.method == "GET" && get.is_none() {
+                    get = Some((route, params));
+                }
+                if !allowed.contains(&route.method.as_ref()) {
+                    allowed.push(&route.method);
+                }
+            }
+        }
+        if request.method == "HEAD" {
+            if let Some((route, params)) = get {
+                request.path_params = params;
+                return (route.handler)(request);
+            }
+        }
+        if allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+        let response = if allowed.is_empty() {
+            Status::not_found()
+        } else {
+            let mut response = Status::method_not_allowed();
+            response.header("Allow", &allowed.join(", "));
+            response
+        };
+        let hook = self.errors.iter()
+            .filter(|&(prefix, _)| is_prefix(prefix, &path))
+            .max_by_key(|&(prefix, _)| prefix.len());
+        match hook {
+            Some((_, hook)) => hook(response),
+            None => response,
+        }
+    }
+}
+
+/// Does `path` start with the segments of `prefix`
+fn is_prefix(prefix: &[Segment], path: &[&str]) -> bool {
+    prefix.len() <= path.len() && prefix.iter().zip(path.iter()).all(|(segment, part)| match *segment {
+        Segment::Literal(ref lit) => lit == part,
+        Segment::Param(_) | Segment::Rest(_) => true,
+    })
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            if let Some(name) = s.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = s.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(s.to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, url: &str) -> Request {
+        let mut request = Request::new();
+        request.method = method.to_string();
+        request.url = url.to_string();
+        request
+    }
+
+    fn text(body: &str) -> Response {
+        let mut response = Status::ok();
+        response.body(body.as_bytes().to_vec());
+        response
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |_: &Request| text("index"));
+        router.get("/game/:id", |req: &Request| text(&format!("game {}", req.path_params["id"])));
+        router.post("/game/:id/move", |req: &Request| text(&format!("move {}", req.path_params["id"])));
+        router.get("/static/*path", |req: &Request| text(&req.path_params["path"]));
+        router
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8_lossy(response.body.as_ref().unwrap()).into_owned()
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        let router = router();
+        assert_eq!(body(&router.dispatch(&mut request("GET", "/"))), "index");
+        assert_eq!(body(&router.dispatch(&mut request("GET", "/game/12/"))), "game 12");
+        assert_eq!(body(&router.dispatch(&mut request("POST", "/game/12/move"))), "move 12");
+        assert_eq!(body(&router.dispatch(&mut request("GET", "/static/css/main.css"))), "css/main.css");
+    }
+
+    #[test]
+    fn not_found() {
+        let router = router();
+        for url in &["/nothing", "/game", "/game/12/move/again"] {
+            let response = router.dispatch(&mut request("GET", url));
+            assert_eq!(response.status_code(), 404, "{}", url);
+            assert!(response.headers.get("Allow").is_none());
+        }
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let router = router();
+        let response = router.dispatch(&mut request("DELETE", "/game/12"));
+        assert_eq!(response.status_code(), 405);
+        assert_eq!(response.headers.get("Allow").map(|a| a.as_str()), Some("GET, HEAD"));
+        let response = router.dispatch(&mut request("GET", "/game/12/move"));
+        assert_eq!(response.status_code(), 405);
+        assert_eq!(response.headers.get("Allow").map(|a| a.as_str()), Some("POST"));
+    }
+
+    #[test]
+    fn head_uses_the_get_route() {
+        let router = router();
+        let mut response = router.dispatch(&mut request("HEAD", "/game/12"));
+        assert_eq!(response.status_code(), 200);
+        assert!(response.head);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 7\r\n"), "{}", out);
+        assert!(out.ends_with("\r\n\r\n"), "{}", out);
+        // Only for GET routes
+        let response = router.dispatch(&mut request("HEAD", "/game/12/move"));
+        assert_eq!(response.status_code(), 405);
+
+        // A HEAD route of its own is preferred
+        let mut router = router;
+        router.route("HEAD", "/game/:id", |_: &Request| Status::no_content());
+        assert_eq!(router.dispatch(&mut request("HEAD", "/game/12")).status_code(), 204);
+    }
+
+    #[test]
+    fn mounted_error_hooks() {
+        let mut api = Router::new();
+        api.get("/games/:id", |_: &Request| text("game"));
+        api.errors("/", |mut response: Response| {
+            response.body(b"api error".to_vec());
+            response
+        });
+        let mut router = router();
+        router.mount("/api", api);
+        assert_eq!(body(&router.dispatch(&mut request("GET", "/api/games/1"))), "game");
+        let response = router.dispatch(&mut request("GET", "/api/nothing"));
+        assert_eq!(response.status_code(), 404);
+        assert_eq!(body(&response), "api error");
+        let response = router.dispatch(&mut request("POST", "/api/games/1"));
+        assert_eq!(response.status_code(), 405);
+        assert_eq!(body(&response), "api error");
+        // Urls outside the mount keep the usual responses
+        let response = router.dispatch(&mut request("GET", "/apis"));
+        assert_eq!(response.status_code(), 404);
+        assert!(response.body.as_ref().map(|b| &b[..] != b"api error").unwrap_or(true));
+    }
+}
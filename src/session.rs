@@ -0,0 +1,63 @@
+//! Signed session cookies
+//!
+//! The cookie holds the user id the game server gave out, and an HMAC of
+//! it made with a key only the web server knows. A client can read its id
+//! but can't change it to another user's without the signature failing.
+//!
+//! cookie value: "<user_id>.<hex HMAC-SHA-256 of user_id>"
+//!
+
+use common::{Cookie, Request, SameSite};
+use common::crypto;
+use std::env;
+
+/// Name of the cookie holding the session
+pub const COOKIE: &str = "session";
+
+pub struct Sessions {
+    key: Vec<u8>,
+}
+impl Sessions {
+    /// Sign with the key in `SESSION_SECRET`, or a random key if unset.
+    /// A random key means sessions don't survive a restart.
+    pub fn from_env() -> Sessions {
+        let key = match env::var("SESSION_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => crypto::random_bytes(32),
+        };
+        Sessions { key }
+    }
+    /// The cookie value for a user
+    pub fn sign(&self, user_id: u32) -> String {
+        let id = user_id.to_string();
+        let mac = crypto::hmac_sha256(&self.key, id.as_bytes());
+        format!("{}.{}", id, crypto::to_hex(&mac))
+    }
+    /// The user id in a cookie value, if its signature is good
+    pub fn verify(&self, value: &str) -> Option<u32> {
+        let mut split = value.splitn(2, '.');
+        let id = split.next().unwrap_or("");
+        let sig = split.next().unwrap_or("");
+        let mac = crypto::hmac_sha256(&self.key, id.as_bytes());
+        if !crypto::constant_time_eq(crypto::to_hex(&mac).as_bytes(), sig.as_bytes()) {
+            return None;
+        }
+        id.parse::<u32>().ok()
+    }
+    /// The user id from a request's session cookie, None if there is no
+    /// cookie or it has been tampered with
+    pub fn user_id(&self, request: &Request) -> Option<u32> {
+        match request.get_cookie(COOKIE) {
+            Some(value) => self.verify(value),
+            None => None,
+        }
+    }
+    /// The cookie that starts a session for `user_id`
+    pub fn cookie(&self, user_id: u32) -> Cookie {
+        let mut cookie = Cookie::new(COOKIE, &self.sign(user_id));
+        cookie.path = Some("/".to_string());
+        cookie.http_only = true;
+        cookie.same_site = Some(SameSite::Lax);
+        cookie
+    }
+}
@@ -0,0 +1,99 @@
+//! Graceful shutdown on SIGINT/SIGTERM
+//!
+//! `install` registers a signal handler that only sets a flag, the accept
+//! loop (via `incoming`) and connection handlers check `requested` and wind
+//! down on their own, so no request is cut off half way through.
+//!
+//! example:
+//!    shutdown::install();
+//!    for stream in shutdown::incoming(&listener).unwrap() {
+//!        // ...
+//!    }
+//!    // listener has stopped, drain the workers
+//!
+
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often the accept loop checks for a shutdown while no one connects
+const POLL_MILLIS: u64 = 50;
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const SIGINT : c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    extern "C" {
+        // From libc, which std already links on unix
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+}
+
+/// Storing to an atomic is all a signal handler can safely do
+#[cfg(unix)]
+extern "C" fn on_signal(_signum: ::std::os::raw::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGINT and SIGTERM, after this they request a shutdown instead of
+/// killing the process. Does nothing on platforms without signals.
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGINT, on_signal);
+        sys::signal(sys::SIGTERM, on_signal);
+    }
+}
+
+/// Has a shutdown been asked for
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Ask for a shutdown, as if a signal was received
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Like `TcpListener::incoming`, but ends once a shutdown is requested.
+/// The listener is switched to non-blocking so it can check between
+/// connections, the streams it gives out are blocking as usual.
+pub fn incoming<'a>(listener: &'a TcpListener) -> io::Result<Incoming<'a>> {
+    listener.set_nonblocking(true)?;
+    Ok(Incoming { listener })
+}
+
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        loop {
+            if requested() {
+                return None;
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // Some platforms pass non-blocking on to accepted streams
+                    return match stream.set_nonblocking(false) {
+                        Ok(_) => Some(Ok(stream)),
+                        Err(e) => Some(Err(e)),
+                    };
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(POLL_MILLIS));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! Serving files from a directory on disk, eg css, scripts and images
+//!
+//! The url path after the prefix is looked up under the root directory.
+//! Paths that would leave the root (`..`, or a symlink pointing outside
+//! it) are refused, as are hidden files. Responses carry an `ETag` and
+//! `Last-Modified` so clients can revalidate with a 304, and a single
+//! `Range` is answered with a 206.
+//!
+//! example:
+//!    let files = StaticFiles::new("/static", "static").unwrap();
+//!    let pattern = files.pattern();
+//!    router.get(&pattern, move |request: &Request| files.serve(request));
+//!
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use {Request, Response, Status, http_date, parse_http_date, percent_decode};
+
+pub struct StaticFiles {
+    /// The url path files are served under, eg "/static"
+    pub prefix: String,
+    /// The directory files are served from
+    pub root  : PathBuf,
+    /// The file served for a url naming a directory
+    pub index : String,
+}
+impl StaticFiles {
+    /// Serve the directory `root` under `prefix`, an error if `root` isn't
+    /// a directory
+    pub fn new(prefix: &str, root: &str) -> Result<StaticFiles, String> {
+        // Resolved once so every served path can be checked against it. A
+        // root left relative would never match, refusing every file.
+        let root = match fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(e) => return Err(format!("Static root {:?}: {}", root, e)),
+        };
+        if !root.is_dir() {
+            return Err(format!("Static root {:?} is not a directory", root));
+        }
+        Ok(StaticFiles {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root,
+            index : "index.html".to_string(),
+        })
+    }
+    /// Serve `STATIC_ROOT` (default "static") under `STATIC_PREFIX`
+    /// (default "/static")
+    pub fn from_env() -> Result<StaticFiles, String> {
+        let prefix = env::var("STATIC_PREFIX").unwrap_or_else(|_| "/static".to_string());
+        let root = env::var("STATIC_ROOT").unwrap_or_else(|_| "static".to_string());
+        StaticFiles::new(&prefix, &root)
+    }
+    /// The `Router` pattern to serve these files on, the rest of the url
+    /// is captured as the `path` param
+    pub fn pattern(&self) -> String {
+        format!("{}/*path", self.prefix)
+    }
+    /// Answer a GET for a file
+    pub fn serve(&self, request: &Request) -> Response {
+        let rel = match request.path_params.get("path") {
+            Some(path) => path.clone(),
+            None => String::new(),
+        };
+        // A '+' in a path is a plus, not a space as in a query string
+        let rel = percent_decode(&rel.replace('+', "%2B"));
+        let mut path = self.root.clone();
+        for segment in rel.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue;
+            }
+            if segment == ".." || segment.contains('\\') || segment.contains('\0') {
+                return Status::forbidden();
+            }
+            // Hidden files, eg .git or .env, are never served
+            if segment.starts_with('.') {
+                return Status::not_found();
+            }
+            path.push(segment);
+        }
+        // Following symlinks may still lead out of the root
+        let mut path = match fs::canonicalize(&path) {
+            Ok(path) => path,
+            Err(_) => return Status::not_found(),
+        };
+        if !path.starts_with(&self.root) {
+            return Status::forbidden();
+        }
+
+        if path.is_dir() {
+            // Relative links in the index only work from a url ending in '/'
+            if !request.url.ends_with('/') {
+                let mut response = Status::moved_permanently();
+                response.header("Location", &(request.url.clone() + "/"));
+                return response;
+            }
+            path.push(&self.index);
+            if !path.is_file() {
+                return Status::forbidden();
+            }
+        }
+        self.serve_file(request, &path)
+    }
+
+    fn serve_file(&self, request: &Request, path: &Path) -> Response {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Status::not_found(),
+        };
+        let meta = match file.metadata() {
+            Ok(meta) => meta,
+            Err(_) => return Status::internal_error(),
+        };
+        let len = meta.len();
+        let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+        let mtime = match modified.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        };
+        let etag = format!("\"{:x}-{:x}\"", len, mtime);
+        let last_modified = http_date(modified);
+
+        // If-None-Match takes the place of If-Modified-Since when both are sent
+        let not_modified = match request.get_header("If-None-Match") {
+            Some(tags) => tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            }),
+            None => match request.get_header("If-Modified-Since").and_then(|d| parse_http_date(d)) {
+                Some(since) => modified.duration_since(since).map(|d| d.as_secs() == 0).unwrap_or(true),
+                None => false,
+            },
+        };
+        if not_modified {
+            let mut response = Status::not_modified();
+            response.header("ETag", &etag);
+            response.header("Last-Modified", &last_modified);
+            return response;
+        }
+
+        // A Range is only used if the file is still the one If-Range names
+        let range = match request.get_header("Range") {
+            Some(range) => match request.get_header("If-Range") {
+                Some(tag) if *tag != etag && *tag != last_modified => Range::Full,
+                _ => parse_range(range, len),
+            },
+            None => Range::Full,
+        };
+        let mut response = match range {
+            Range::Full => {
+                let mut response = Status::ok();
+                response.stream(file, Some(len));
+                response
+            }
+            Range::Part(start, end) => {
+                if file.seek(SeekFrom::Start(start)).is_err() {
+                    return Status::internal_error();
+                }
+                let mut response = Status::partial_content();
+                response.header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+                response.stream(file.take(end - start + 1), Some(end - start + 1));
+                response
+            }
+            Range::Unsatisfiable => {
+                let mut response = Status::range_not_satisfiable();
+                response.header("Content-Range", &format!("bytes */{}", len));
+                return response;
+            }
+        };
+        response.header("Content-Type", content_type(path));
+        response.header("ETag", &etag);
+        response.header("Last-Modified", &last_modified);
+        response.header("Accept-Ranges", "bytes");
+        response
+    }
+}
+
+/// What part of a file a `Range` header asks for
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// No usable range, send the whole file. Several ranges at once
+    /// are not supported so they are also answered with the whole file.
+    Full,
+    /// The first and last byte, inclusive
+    Part(u64, u64),
+    /// The range starts past the end of the file
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header, eg "bytes=0-499", "bytes=500-" or "bytes=-500"
+/// for the last 500 bytes, against a file of `len` bytes
+fn parse_range(header: &str, len: u64) -> Range {
+    let spec = match header.trim().splitn(2, '=').collect::<Vec<&str>>()[..] {
+        ["bytes", spec] if !spec.contains(',') => spec.trim(),
+        _ => return Range::Full,
+    };
+    let (first, last) = match spec.find('-') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => return Range::Full,
+    };
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if len == 0 => Range::Unsatisfiable,
+            Ok(n) => Range::Part(len.saturating_sub(n), len - 1),
+            Err(_) => Range::Full,
+        };
+    }
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Range::Full,
+    };
+    let end = if last.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match last.parse::<u64>() {
+            Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+            _ => return Range::Full,
+        }
+    };
+    if start >= len {
+        return Range::Unsatisfiable;
+    }
+    Range::Part(start, end)
+}
+
+/// The MIME type for a file, by its extension
+fn content_type(path: &Path) -> &'static str {
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match ext.as_ref() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A root holding a couple of files, beside a secret outside it
+    fn files(name: &str) -> (StaticFiles, PathBuf) {
+        let dir = env::temp_dir().join(format!("tictac-static-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/css")).unwrap();
+        fs::write(dir.join("root/css/main.css"), "body { color: red }").unwrap();
+        fs::write(dir.join("root/.env"), "SESSION_SECRET=x").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let files = StaticFiles::new("/static/", dir.join("root").to_str().unwrap()).unwrap();
+        (files, dir)
+    }
+
+    fn get(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new();
+        request.method = "GET".to_string();
+        request.url = format!("/static/{}", path);
+        request.path_params.insert("path".to_string(), path.to_string());
+        for &(name, val) in headers {
+            request.headers.insert(name, val).unwrap();
+        }
+        files.serve(&request)
+    }
+
+    /// The body as written to the client
+    fn body(mut response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out[out.find("\r\n\r\n").unwrap() + 4..].to_string()
+    }
+
+    #[test]
+    fn missing_root_is_an_error() {
+        assert!(StaticFiles::new("/static", "/no/such/static/root").is_err());
+        let (_, dir) = files("missing");
+        assert!(StaticFiles::new("/static", dir.join("secret.txt").to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serves_files_under_the_root() {
+        let (files, dir) = files("serve");
+        assert_eq!(files.prefix, "/static");
+        let response = get(&files, "css/main.css", &[]);
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.headers.get("Content-Type").map(|t| t.as_str()), Some("text/css; charset=utf-8"));
+        assert_eq!(body(response), "body { color: red }");
+        assert_eq!(get(&files, "css/./main.css", &[]).status_code(), 200);
+        assert_eq!(get(&files, "css/missing.css", &[]).status_code(), 404);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paths_out_of_the_root_are_refused() {
+        let (files, dir) = files("escape");
+        assert_eq!(get(&files, "../secret.txt", &[]).status_code(), 403);
+        assert_eq!(get(&files, "css/../../secret.txt", &[]).status_code(), 403);
+        assert_eq!(get(&files, "%2e%2e/secret.txt", &[]).status_code(), 403);
+        assert_eq!(get(&files, "css\\..\\..\\secret.txt", &[]).status_code(), 403);
+        // Hidden files aren't served, nor found
+        assert_eq!(get(&files, ".env", &[]).status_code(), 404);
+        assert_eq!(get(&files, "%2eenv", &[]).status_code(), 404);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_refused() {
+        use std::os::unix::fs::symlink;
+        let (files, dir) = files("symlink");
+        symlink(dir.join("secret.txt"), dir.join("root/leak.txt")).unwrap();
+        symlink(dir.as_path(), dir.join("root/up")).unwrap();
+        symlink(dir.join("root/css/main.css"), dir.join("root/inside.css")).unwrap();
+        assert_eq!(get(&files, "leak.txt", &[]).status_code(), 403);
+        assert_eq!(get(&files, "up/secret.txt", &[]).status_code(), 403);
+        assert_eq!(get(&files, "inside.css", &[]).status_code(), 200);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Range::Part(0, 4));
+        assert_eq!(parse_range("bytes=5-", 10), Range::Part(5, 9));
+        assert_eq!(parse_range("bytes=5-100", 10), Range::Part(5, 9));
+        // The last n bytes
+        assert_eq!(parse_range("bytes=-3", 10), Range::Part(7, 9));
+        assert_eq!(parse_range("bytes=-30", 10), Range::Part(0, 9));
+        assert_eq!(parse_range("bytes=-0", 10), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=10-", 10), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), Range::Unsatisfiable);
+        // Several ranges, or ones that can't be read, get the whole file
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Range::Full);
+        assert_eq!(parse_range("bytes=5-2", 10), Range::Full);
+        assert_eq!(parse_range("bytes=a-b", 10), Range::Full);
+        assert_eq!(parse_range("items=0-4", 10), Range::Full);
+
+        let (files, dir) = files("range");
+        let response = get(&files, "css/main.css", &[("Range", "bytes=0-3")]);
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(response.headers.get("Content-Range").map(|r| r.as_str()), Some("bytes 0-3/19"));
+        assert_eq!(body(response), "body");
+        assert_eq!(body(get(&files, "css/main.css", &[("Range", "bytes=-3")])), "d }");
+        let response = get(&files, "css/main.css", &[("Range", "bytes=50-")]);
+        assert_eq!(response.status_code(), 416);
+        assert_eq!(response.headers.get("Content-Range").map(|r| r.as_str()), Some("bytes */19"));
+        // A stale If-Range gets the whole file
+        let response = get(&files, "css/main.css", &[("Range", "bytes=0-3"), ("If-Range", "\"old\"")]);
+        assert_eq!(response.status_code(), 200);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revalidation() {
+        let (files, dir) = files("revalidate");
+        let response = get(&files, "css/main.css", &[]);
+        let etag = response.headers.get("ETag").unwrap().clone();
+        let modified = response.headers.get("Last-Modified").unwrap().clone();
+
+        let response = get(&files, "css/main.css", &[("If-None-Match", &etag)]);
+        assert_eq!(response.status_code(), 304);
+        assert_eq!(body(response), "");
+        let weak = format!("\"other\", W/{}", etag);
+        assert_eq!(get(&files, "css/main.css", &[("If-None-Match", &weak)]).status_code(), 304);
+        assert_eq!(get(&files, "css/main.css", &[("If-None-Match", "*")]).status_code(), 304);
+        assert_eq!(get(&files, "css/main.css", &[("If-None-Match", "\"other\"")]).status_code(), 200);
+
+        assert_eq!(get(&files, "css/main.css", &[("If-Modified-Since", &modified)]).status_code(), 304);
+        let old = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(get(&files, "css/main.css", &[("If-Modified-Since", old)]).status_code(), 200);
+        // If-None-Match wins over If-Modified-Since
+        let both = [("If-None-Match", "\"other\""), ("If-Modified-Since", modified.as_str())];
+        assert_eq!(get(&files, "css/main.css", &both).status_code(), 200);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! Where the game_server keeps its games between restarts
+//!
+//! Games are always played from memory, in `TicTacBoard`, and each change
+//! made there is handed to a `GameStore` as a `Change`. Set GAME_STORE to
+//! pick the store:
+//!
+//! ```text
+//! memory  (default) nothing is kept, a restart loses every game
+//! file    games are kept in GAME_DATA_DIR (default "data"), as a snapshot
+//!         of the whole board in snapshot.json plus a log of the changes
+//!         made since in changes.log, one JSON change per line
+//! ```
+//!
+//! On start up the file store reads the snapshot and replays the log over
+//! it. Every change sets something to its new value, so replaying a change
+//! the snapshot already has does no harm, and a last line cut short by a
+//! crash is skipped. The log is folded in to a new snapshot on start up,
+//! once it reaches `COMPACT_AFTER` changes, and on shutdown.
+//!
+
+use rustc_serialize::json;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use {TicTacBoard, Game};
+
+/// Changes logged before the file store writes a new snapshot, unless
+/// another number is given to `FileStore::with_compact_after`
+const COMPACT_AFTER: usize = 1000;
+
+/// One change to the board, as it is saved
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum Change {
+    /// A user id was given out, and when
+    User(u32, u64),
+    /// A game was started or changed, this is all of it as it is now
+    Game(u32, Game),
+    /// The game a user plays when they don't name one
+    Current(u32, u32),
+    /// A game was abandoned
+    Remove(u32),
+    /// A user id with no games was forgotten
+    RemoveUser(u32),
+}
+
+impl TicTacBoard {
+    /// Make a saved change, the same way it was made before it was saved
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::User(user_id, time) => {
+                self.users.insert(user_id, time);
+            }
+            Change::Game(game_id, game) => {
+                self.board.insert(game_id, game);
+            }
+            Change::Current(user_id, game_id) => {
+                self.current.insert(user_id, game_id);
+            }
+            Change::Remove(game_id) => {
+                self.board.remove(&game_id);
+                self.current.retain(|_, current| *current != game_id);
+            }
+            Change::RemoveUser(user_id) => {
+                self.users.remove(&user_id);
+            }
+        }
+    }
+}
+
+pub trait GameStore: Send {
+    /// The board as it was last saved, read once on start up
+    fn recover(&mut self) -> Result<TicTacBoard, String>;
+    /// Save a change that has just been made to `board`
+    fn record(&mut self, change: &Change, board: &TicTacBoard) -> Result<(), String>;
+    /// Save all of `board`, called on shutdown
+    fn flush(&mut self, board: &TicTacBoard) -> Result<(), String>;
+}
+
+/// The store named by GAME_STORE, see the top of this file
+pub fn from_env() -> Result<Box<dyn GameStore>, String> {
+    match env::var("GAME_STORE").unwrap_or_else(|_| "memory".to_string()).as_ref() {
+        "memory" => Ok(Box::new(MemoryStore)),
+        "file" => {
+            let dir = env::var("GAME_DATA_DIR").unwrap_or_else(|_| "data".to_string());
+            Ok(Box::new(FileStore::new(&dir)))
+        }
+        other => Err(format!("Unknown GAME_STORE {:?}, expected \"memory\" or \"file\"", other)),
+    }
+}
+
+/// Keeps nothing, the board in memory is the only copy
+pub struct MemoryStore;
+impl GameStore for MemoryStore {
+    fn recover(&mut self) -> Result<TicTacBoard, String> {
+        Ok(TicTacBoard::new())
+    }
+    fn record(&mut self, _change: &Change, _board: &TicTacBoard) -> Result<(), String> {
+        Ok(())
+    }
+    fn flush(&mut self, _board: &TicTacBoard) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A snapshot plus a log of changes in a directory
+pub struct FileStore {
+    dir   : PathBuf,
+    /// The log, open for appending once the store has been recovered
+    log   : Option<File>,
+    /// Changes in the log since the last snapshot
+    logged: usize,
+    /// Changes logged before a new snapshot is written
+    compact_after: usize,
+}
+impl FileStore {
+    pub fn new(dir: &str) -> FileStore {
+        FileStore::with_compact_after(dir, COMPACT_AFTER)
+    }
+    /// A store that writes a new snapshot every `compact_after` changes
+    pub fn with_compact_after(dir: &str, compact_after: usize) -> FileStore {
+        FileStore { dir: PathBuf::from(dir), log: None, logged: 0, compact_after }
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join("snapshot.json")
+    }
+    fn log_path(&self) -> PathBuf {
+        self.dir.join("changes.log")
+    }
+
+    /// Write `board` as the new snapshot and empty the log. The snapshot is
+    /// written beside the old one and renamed over it, so a crash part way
+    /// leaves the old snapshot and log as they were.
+    fn compact(&mut self, board: &TicTacBoard) -> Result<(), String> {
+        let text = match json::encode(board) {
+            Ok(o) => o,
+            Err(e) => return Err(format!("JSON conversion failed: {:?}", e)),
+        };
+        let path = self.snapshot_path();
+        let tmp = self.dir.join("snapshot.json.tmp");
+        let written = File::create(&tmp)
+            .and_then(|mut f| f.write_all(text.as_bytes()).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            return Err(format!("Could not write snapshot {:?}: {:?}", path, e));
+        }
+        // Everything in the log is in the snapshot now, so start it again
+        match File::create(self.log_path()) {
+            Ok(log) => {
+                self.log = Some(log);
+                self.logged = 0;
+                Ok(())
+            }
+            Err(e) => Err(format!("Could not open log {:?}: {:?}", self.log_path(), e)),
+        }
+    }
+}
+impl GameStore for FileStore {
+    fn recover(&mut self) -> Result<TicTacBoard, String> {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            return Err(format!("Could not create data directory {:?}: {:?}", self.dir, e));
+        }
+        // A snapshot that can't be read stops the server, rather than
+        // starting empty and overwriting the games in it
+        let mut board = match fs::read_to_string(self.snapshot_path()) {
+            Ok(text) => match json::decode::<TicTacBoard>(&text) {
+                Ok(board) => board,
+                Err(e) => return Err(format!("Snapshot {:?} is corrupt: {:?}", self.snapshot_path(), e)),
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => TicTacBoard::new(),
+            Err(e) => return Err(format!("Could not read snapshot {:?}: {:?}", self.snapshot_path(), e)),
+        };
+
+        let mut replayed = 0;
+        match File::open(self.log_path()) {
+            Ok(file) => {
+                let lines: Vec<String> = match BufReader::new(file).lines().collect() {
+                    Ok(lines) => lines,
+                    Err(e) => return Err(format!("Could not read log {:?}: {:?}", self.log_path(), e)),
+                };
+                for (i, line) in lines.iter().enumerate() {
+                    match json::decode::<Change>(line) {
+                        Ok(change) => {
+                            board.apply(change);
+                            replayed += 1;
+                        }
+                        // The server stopped while writing the last change
+                        Err(_) if i + 1 == lines.len() => {
+                            println!("Skipped an incomplete change at the end of {:?}", self.log_path());
+                        }
+                        Err(e) => return Err(format!("Log {:?} is corrupt at line {}: {:?}",
+                                                     self.log_path(), i + 1, e)),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Could not read log {:?}: {:?}", self.log_path(), e)),
+        }
+        println!("Recovered {} games and {} users from {:?} ({} changes replayed)",
+                 board.board.len(), board.users.len(), self.dir, replayed);
+        self.compact(&board)?;
+        Ok(board)
+    }
+
+    fn record(&mut self, change: &Change, board: &TicTacBoard) -> Result<(), String> {
+        let line = match json::encode(change) {
+            Ok(o) => o + "\n",
+            Err(e) => return Err(format!("JSON conversion failed: {:?}", e)),
+        };
+        {
+            let log = match self.log {
+                Some(ref mut log) => log,
+                None => return Err("The store was not recovered before use".to_string()),
+            };
+            // The change is on disk before the move is answered
+            if let Err(e) = log.write_all(line.as_bytes()).and_then(|_| log.sync_data()) {
+                return Err(format!("Could not write to log {:?}: {:?}", self.log_path(), e));
+            }
+        }
+        self.logged += 1;
+        if self.logged >= self.compact_after {
+            self.compact(board)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, board: &TicTacBoard) -> Result<(), String> {
+        self.compact(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("tictac-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_string()
+    }
+
+    /// Make a change the way TicTacGame does, to the board then the store
+    fn change(store: &mut FileStore, board: &mut TicTacBoard, make: fn() -> Change) {
+        board.apply(make());
+        store.record(&make(), board).unwrap();
+    }
+
+    #[test]
+    fn recover_across_compaction() {
+        let dir = temp_dir("recover");
+        let mut store = FileStore::with_compact_after(&dir, 3);
+        let mut board = store.recover().unwrap();
+        change(&mut store, &mut board, || Change::User(1, 0));
+        change(&mut store, &mut board, || Change::User(2, 0));
+        // The third change writes the snapshot and empties the log
+        change(&mut store, &mut board, || Change::Current(1, 10));
+        assert_eq!(store.logged, 0);
+        assert_eq!(fs::read_to_string(store.log_path()).unwrap(), "");
+        // These are only in the log
+        change(&mut store, &mut board, || Change::User(3, 0));
+        change(&mut store, &mut board, || Change::Current(2, 20));
+        drop(store);
+
+        let recovered = FileStore::new(&dir).recover().unwrap();
+        let mut users: Vec<u32> = recovered.users.keys().cloned().collect();
+        users.sort();
+        assert_eq!(users, vec![1, 2, 3]);
+        assert_eq!(recovered.current.get(&1), Some(&10));
+        assert_eq!(recovered.current.get(&2), Some(&20));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let dir = temp_dir("torn");
+        let mut store = FileStore::new(&dir);
+        let mut board = store.recover().unwrap();
+        change(&mut store, &mut board, || Change::User(1, 0));
+        drop(store);
+        let mut log = fs::OpenOptions::new().append(true).open(PathBuf::from(&dir).join("changes.log")).unwrap();
+        log.write_all(b"{\"variant\":\"User\",\"fie").unwrap();
+
+        let recovered = FileStore::new(&dir).recover().unwrap();
+        assert!(recovered.users.contains_key(&1));
+        assert_eq!(recovered.users.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
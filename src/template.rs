@@ -0,0 +1,652 @@
+//! A small template language for the html pages
+//!
+//! Templates are compiled once, when they are added, and rendered with a
+//! `Json` object holding the variables. Every `{{ }}` is HTML escaped
+//! unless it ends in `|raw`.
+//!
+//! ```text
+//! {{ user.name }}                 a variable, dots look up object fields
+//! {{ html|raw }}                  a variable, not escaped
+//! {% if won %} .. {% else %} .. {% endif %}
+//! {% if not over %} .. {% endif %}
+//! {% if difficulty == "hard" %} .. {% endif %}
+//! {% for row in rows %} .. {% endfor %}   also sets loop.index, loop.first, loop.last
+//! {% include "board.html" %}      render another template in place
+//! {% extends "layout.html" %}     must come first, the blocks below then
+//! {% block content %} .. {% endblock %}   replace the layout's blocks of the same name
+//! {# a comment #}
+//! ```
+//!
+//! A condition is false for null, false, 0, "" or an empty array or
+//! object, and for a variable that isn't set. Printing a variable that
+//! isn't set is an error, so typos are caught rather than rendered blank.
+//!
+//! example:
+//!    let mut templates = Templates::new();
+//!    templates.add("hello.html", "<p>Hello {{ name }}</p>").unwrap();
+//!    let mut vars = BTreeMap::new();
+//!    vars.insert("name".to_string(), "<b>".to_json());
+//!    let html = templates.render("hello.html", &Json::Object(vars)).unwrap();
+//!    assert_eq!(html, "<p>Hello &lt;b&gt;</p>");
+//!
+
+use rustc_serialize::json::Json;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How deep includes and layouts may nest, stops a template including itself
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    /// A template file couldn't be read
+    Io(String, io::Error),
+    /// A template couldn't be compiled, with the line of the error
+    Syntax { template: String, line: usize, message: String },
+    /// No template has this name
+    NotFound(String),
+    /// A variable to print isn't set, or can't be printed
+    Render { template: String, message: String },
+}
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::Io(ref name, ref e) => write!(f, "Could not read template {}: {}", name, e),
+            TemplateError::Syntax { ref template, line, ref message } =>
+                write!(f, "{} line {}: {}", template, line, message),
+            TemplateError::NotFound(ref name) => write!(f, "No template named {:?}", name),
+            TemplateError::Render { ref template, ref message } => write!(f, "{}: {}", template, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cond {
+    not   : bool,
+    path  : Vec<String>,
+    equals: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var { path: Vec<String>, raw: bool },
+    If { cond: Cond, then: Vec<Node>, other: Vec<Node> },
+    For { name: String, path: Vec<String>, body: Vec<Node> },
+    Include(String),
+    Block { name: String, body: Vec<Node> },
+}
+
+/// A compiled template
+#[derive(Debug, Clone)]
+struct Template {
+    extends: Option<String>,
+    nodes  : Vec<Node>,
+}
+
+/// Every template the server uses, by name
+#[derive(Default)]
+pub struct Templates {
+    templates: HashMap<String, Template>,
+}
+impl Templates {
+    pub fn new() -> Templates {
+        Templates { templates: HashMap::new() }
+    }
+    /// Compile every file in `dir`, each named by its file name, eg
+    /// "game.html". Includes and layouts are checked to exist.
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Templates, TemplateError> {
+        let dir = dir.as_ref();
+        let dir_name = dir.display().to_string();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(TemplateError::Io(dir_name, e)),
+        };
+        let mut templates = Templates::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => return Err(TemplateError::Io(dir_name, e)),
+            };
+            if !path.is_file() {
+                continue;
+            }
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let mut source = String::new();
+            if let Err(e) = fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+                return Err(TemplateError::Io(name, e));
+            }
+            templates.add(&name, &source)?;
+        }
+        templates.check()?;
+        Ok(templates)
+    }
+    /// Load the templates in `TEMPLATE_DIR`, default "templates"
+    pub fn from_env() -> Result<Templates, TemplateError> {
+        let dir = env::var("TEMPLATE_DIR").unwrap_or_else(|_| "templates".to_string());
+        Templates::load_dir(dir)
+    }
+    /// Compile a template from its source, replacing any of the same name
+    pub fn add(&mut self, name: &str, source: &str) -> Result<(), TemplateError> {
+        let template = Parser::new(name, source).parse()?;
+        self.templates.insert(name.to_string(), template);
+        Ok(())
+    }
+    /// Check every include and layout names a template that exists
+    pub fn check(&self) -> Result<(), TemplateError> {
+        for template in self.templates.values() {
+            if let Some(ref layout) = template.extends {
+                self.get(layout)?;
+            }
+            self.check_nodes(&template.nodes)?;
+        }
+        Ok(())
+    }
+    fn check_nodes(&self, nodes: &[Node]) -> Result<(), TemplateError> {
+        for node in nodes {
+            match *node {
+                Node::Include(ref name) => { self.get(name)?; }
+                Node::If { ref then, ref other, .. } => {
+                    self.check_nodes(then)?;
+                    self.check_nodes(other)?;
+                }
+                Node::For { ref body, .. } | Node::Block { ref body, .. } => self.check_nodes(body)?,
+                Node::Text(_) | Node::Var { .. } => {}
+            }
+        }
+        Ok(())
+    }
+    fn get(&self, name: &str) -> Result<&Template, TemplateError> {
+        match self.templates.get(name) {
+            Some(template) => Ok(template),
+            None => Err(TemplateError::NotFound(name.to_string())),
+        }
+    }
+    /// Render the template `name`, `vars` should be a `Json::Object`
+    pub fn render(&self, name: &str, vars: &Json) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut render = Render {
+            templates: self,
+            name     : name.to_string(),
+            vars,
+            scopes   : Vec::new(),
+            blocks   : HashMap::new(),
+            depth    : 0,
+        };
+        render.template(name, &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Replace the characters that could open a tag or end an attribute
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The state of one call to `Templates::render`
+struct Render<'a> {
+    templates: &'a Templates,
+    /// The template being rendered, for errors
+    name     : String,
+    vars     : &'a Json,
+    /// Loop variables, innermost last
+    scopes   : Vec<(String, Json)>,
+    /// Blocks given by the templates extending the one being rendered,
+    /// the first (most derived) one of a name wins
+    blocks   : HashMap<String, &'a [Node]>,
+    depth    : usize,
+}
+impl<'a> Render<'a> {
+    fn template(&mut self, name: &str, out: &mut String) -> Result<(), TemplateError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error(format!("Includes nested too deep at {:?}", name)));
+        }
+        let templates = self.templates;
+        let template = templates.get(name)?;
+        let outer = ::std::mem::replace(&mut self.name, name.to_string());
+        self.depth += 1;
+        let result = match template.extends {
+            // Only the blocks are kept, the layout is rendered in their place
+            Some(ref layout) => {
+                collect_blocks(&template.nodes, &mut self.blocks);
+                self.template(layout, out)
+            }
+            None => self.nodes(&template.nodes, out),
+        };
+        self.depth -= 1;
+        self.name = outer;
+        result
+    }
+
+    fn nodes(&mut self, nodes: &'a [Node], out: &mut String) -> Result<(), TemplateError> {
+        for node in nodes {
+            match *node {
+                Node::Text(ref text) => out.push_str(text),
+                Node::Var { ref path, raw } => {
+                    let text = match self.lookup(path) {
+                        Some(value) => display(&value),
+                        None => None,
+                    };
+                    match text {
+                        Some(text) if raw => out.push_str(&text),
+                        Some(text) => out.push_str(&escape_html(&text)),
+                        None => return Err(self.error(format!("Can't print {:?}", path.join(".")))),
+                    }
+                }
+                Node::If { ref cond, ref then, ref other } => {
+                    let value = self.lookup(&cond.path);
+                    let mut pass = match cond.equals {
+                        Some(ref literal) => match value {
+                            Some(ref value) => display(value).as_ref() == Some(literal),
+                            None => false,
+                        },
+                        None => value.map(|v| truthy(&v)).unwrap_or(false),
+                    };
+                    if cond.not {
+                        pass = !pass;
+                    }
+                    self.nodes(if pass { then } else { other }, out)?;
+                }
+                Node::For { ref name, ref path, ref body } => {
+                    let items = match self.lookup(path) {
+                        Some(Json::Array(items)) => items,
+                        Some(Json::Null) | None => Vec::new(),
+                        Some(_) => return Err(self.error(format!("Can't loop over {:?}", path.join(".")))),
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut info = ::std::collections::BTreeMap::new();
+                        info.insert("index".to_string(), Json::U64(i as u64));
+                        info.insert("first".to_string(), Json::Boolean(i == 0));
+                        info.insert("last".to_string(), Json::Boolean(i + 1 == count));
+                        self.scopes.push(("loop".to_string(), Json::Object(info)));
+                        self.scopes.push((name.clone(), item));
+                        let result = self.nodes(body, out);
+                        self.scopes.pop();
+                        self.scopes.pop();
+                        result?;
+                    }
+                }
+                Node::Include(ref name) => self.template(name, out)?,
+                Node::Block { ref name, ref body } => {
+                    let body = match self.blocks.get(name) {
+                        Some(body) => *body,
+                        None => &body[..],
+                    };
+                    self.nodes(body, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value at `path`, loop variables are looked at before `vars`
+    fn lookup(&self, path: &[String]) -> Option<Json> {
+        let first = &path[0];
+        let mut value = match self.scopes.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.vars.find(first)?,
+        };
+        for key in &path[1..] {
+            value = match *value {
+                Json::Object(ref map) => map.get(key)?,
+                Json::Array(ref items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(value.clone())
+    }
+
+    fn error(&self, message: String) -> TemplateError {
+        TemplateError::Render { template: self.name.clone(), message }
+    }
+}
+
+/// Add the blocks in `nodes` that aren't already given
+fn collect_blocks<'a>(nodes: &'a [Node], blocks: &mut HashMap<String, &'a [Node]>) {
+    for node in nodes {
+        if let Node::Block { ref name, ref body } = *node {
+            blocks.entry(name.clone()).or_insert(&body[..]);
+            collect_blocks(body, blocks);
+        }
+    }
+}
+
+/// The text printed for a value, None for arrays and objects
+fn display(value: &Json) -> Option<String> {
+    match *value {
+        Json::String(ref s) => Some(s.clone()),
+        Json::I64(n) => Some(n.to_string()),
+        Json::U64(n) => Some(n.to_string()),
+        Json::F64(n) => Some(n.to_string()),
+        Json::Boolean(b) => Some(b.to_string()),
+        Json::Null => Some(String::new()),
+        Json::Array(_) | Json::Object(_) => None,
+    }
+}
+
+fn truthy(value: &Json) -> bool {
+    match *value {
+        Json::String(ref s) => !s.is_empty(),
+        Json::I64(n) => n != 0,
+        Json::U64(n) => n != 0,
+        Json::F64(n) => n != 0.0,
+        Json::Boolean(b) => b,
+        Json::Null => false,
+        Json::Array(ref items) => !items.is_empty(),
+        Json::Object(ref map) => !map.is_empty(),
+    }
+}
+
+/// A `{% %}` tag, split in to words
+enum Tag {
+    If(Cond),
+    Else,
+    EndIf,
+    For(String, Vec<String>),
+    EndFor,
+    Include(String),
+    Extends(String),
+    Block(String),
+    EndBlock,
+}
+
+/// What ended a list of nodes
+#[derive(PartialEq)]
+enum Stop {
+    Eof,
+    Else,
+    EndIf,
+    EndFor,
+    EndBlock,
+}
+
+struct Parser<'s> {
+    name: String,
+    src : &'s str,
+    pos : usize,
+}
+impl<'s> Parser<'s> {
+    fn new(name: &str, src: &'s str) -> Parser<'s> {
+        Parser { name: name.to_string(), src, pos: 0 }
+    }
+
+    fn parse(mut self) -> Result<Template, TemplateError> {
+        let (nodes, end, extends) = self.nodes(true)?;
+        if end != Stop::Eof {
+            return Err(self.error("Closing tag without an opening tag"));
+        }
+        Ok(Template { extends, nodes })
+    }
+
+    /// Parse nodes up to the end of the source or a closing tag. `top` is
+    /// true for the outermost list, the only place `extends` is allowed.
+    fn nodes(&mut self, top: bool) -> Result<(Vec<Node>, Stop, Option<String>), TemplateError> {
+        let mut nodes = Vec::new();
+        let mut extends = None;
+        loop {
+            let rest = &self.src[self.pos..];
+            let next = ["{{", "{%", "{#"].iter().filter_map(|open| rest.find(open)).min();
+            let start = match next {
+                Some(start) => start,
+                None => {
+                    push_text(&mut nodes, rest);
+                    self.pos = self.src.len();
+                    return Ok((nodes, Stop::Eof, extends));
+                }
+            };
+            push_text(&mut nodes, &rest[..start]);
+            self.pos += start;
+            let open = &self.src[self.pos..self.pos + 2];
+            let close = match open { "{{" => "}}", "{%" => "%}", _ => "#}" };
+            let inner_len = match self.src[self.pos + 2..].find(close) {
+                Some(len) => len,
+                None => return Err(self.error(&format!("{:?} is never closed", open))),
+            };
+            let inner = self.src[self.pos + 2..self.pos + 2 + inner_len].trim();
+            let tag_pos = self.pos;
+            self.pos += 2 + inner_len + 2;
+
+            match open {
+                "{#" => {}
+                "{{" => {
+                    let (expr, raw) = match inner.find('|') {
+                        Some(i) if inner[i + 1..].trim() == "raw" => (inner[..i].trim(), true),
+                        Some(_) => return Err(self.error_at(tag_pos, "The only filter is |raw")),
+                        None => (inner, false),
+                    };
+                    let path = self.path(expr, tag_pos)?;
+                    nodes.push(Node::Var { path, raw });
+                }
+                _ => match self.tag(inner, tag_pos)? {
+                    Tag::If(cond) => {
+                        let (then, end, _) = self.nodes(false)?;
+                        let other = match end {
+                            Stop::EndIf => Vec::new(),
+                            Stop::Else => {
+                                let (other, end, _) = self.nodes(false)?;
+                                if end != Stop::EndIf {
+                                    return Err(self.error_at(tag_pos, "{% else %} without {% endif %}"));
+                                }
+                                other
+                            }
+                            _ => return Err(self.error_at(tag_pos, "{% if %} without {% endif %}")),
+                        };
+                        nodes.push(Node::If { cond, then, other });
+                    }
+                    Tag::For(name, path) => {
+                        let (body, end, _) = self.nodes(false)?;
+                        if end != Stop::EndFor {
+                            return Err(self.error_at(tag_pos, "{% for %} without {% endfor %}"));
+                        }
+                        nodes.push(Node::For { name, path, body });
+                    }
+                    Tag::Block(name) => {
+                        let (body, end, _) = self.nodes(false)?;
+                        if end != Stop::EndBlock {
+                            return Err(self.error_at(tag_pos, "{% block %} without {% endblock %}"));
+                        }
+                        nodes.push(Node::Block { name, body });
+                    }
+                    Tag::Include(name) => nodes.push(Node::Include(name)),
+                    Tag::Extends(name) => {
+                        let first = nodes.iter().all(|node| match *node {
+                            Node::Text(ref text) => text.trim().is_empty(),
+                            _ => false,
+                        });
+                        if !top || !first || extends.is_some() {
+                            return Err(self.error_at(tag_pos, "{% extends %} must be the first tag"));
+                        }
+                        extends = Some(name);
+                    }
+                    Tag::Else => return Ok((nodes, Stop::Else, extends)),
+                    Tag::EndIf => return Ok((nodes, Stop::EndIf, extends)),
+                    Tag::EndFor => return Ok((nodes, Stop::EndFor, extends)),
+                    Tag::EndBlock => return Ok((nodes, Stop::EndBlock, extends)),
+                },
+            }
+        }
+    }
+
+    fn tag(&self, inner: &str, pos: usize) -> Result<Tag, TemplateError> {
+        let words: Vec<&str> = inner.split_whitespace().collect();
+        let tag = match words[..] {
+            ["if", "not", path] => Tag::If(Cond { not: true, path: self.path(path, pos)?, equals: None }),
+            ["if", path] => Tag::If(Cond { not: false, path: self.path(path, pos)?, equals: None }),
+            ["if", path, op, literal] if op == "==" || op == "!=" => Tag::If(Cond {
+                not   : op == "!=",
+                path  : self.path(path, pos)?,
+                equals: Some(self.string(literal, pos)?),
+            }),
+            ["else"] => Tag::Else,
+            ["endif"] => Tag::EndIf,
+            ["for", name, "in", path] => Tag::For(name.to_string(), self.path(path, pos)?),
+            ["endfor"] => Tag::EndFor,
+            ["include", name] => Tag::Include(self.string(name, pos)?),
+            ["extends", name] => Tag::Extends(self.string(name, pos)?),
+            ["block", name] => Tag::Block(name.to_string()),
+            ["endblock"] => Tag::EndBlock,
+            _ => return Err(self.error_at(pos, &format!("Unknown tag {{% {} %}}", inner))),
+        };
+        Ok(tag)
+    }
+
+    /// A dotted variable path, eg "game.status"
+    fn path(&self, expr: &str, pos: usize) -> Result<Vec<String>, TemplateError> {
+        let path: Vec<String> = expr.split('.').map(|s| s.to_string()).collect();
+        let valid = path.iter().all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+        });
+        if !valid {
+            return Err(self.error_at(pos, &format!("Invalid variable {:?}", expr)));
+        }
+        Ok(path)
+    }
+
+    /// A double quoted string, eg "layout.html"
+    fn string(&self, word: &str, pos: usize) -> Result<String, TemplateError> {
+        if word.len() >= 2 && word.starts_with('"') && word.ends_with('"') {
+            Ok(word[1..word.len() - 1].to_string())
+        } else {
+            Err(self.error_at(pos, &format!("Expected a quoted string, found {}", word)))
+        }
+    }
+
+    fn error(&self, message: &str) -> TemplateError {
+        self.error_at(self.pos, message)
+    }
+    fn error_at(&self, pos: usize, message: &str) -> TemplateError {
+        TemplateError::Syntax {
+            template: self.name.clone(),
+            line    : self.src[..pos].matches('\n').count() + 1,
+            message : message.to_string(),
+        }
+    }
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &str) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(text.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render `source` as "test.html" with `vars` given as JSON text
+    fn render(source: &str, vars: &str) -> Result<String, TemplateError> {
+        let mut templates = Templates::new();
+        templates.add("test.html", source)?;
+        templates.render("test.html", &Json::from_str(vars).unwrap())
+    }
+
+    fn syntax_line(source: &str) -> usize {
+        match render(source, "{}") {
+            Err(TemplateError::Syntax { line, .. }) => line,
+            other => panic!("{:?} gave {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn variables_are_escaped() {
+        assert_eq!(escape_html(r#"<a href="x">'&'</a>"#),
+                   "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+        let vars = r#"{"name": "<b>&</b>", "user": {"id": 7}}"#;
+        assert_eq!(render("<p>{{ name }}</p>", vars).unwrap(), "<p>&lt;b&gt;&amp;&lt;/b&gt;</p>");
+        assert_eq!(render("<p>{{ name|raw }}</p>", vars).unwrap(), "<p><b>&</b></p>");
+        assert_eq!(render("{{user.id}} {# not shown #}", vars).unwrap(), "7 ");
+    }
+
+    #[test]
+    fn conditionals() {
+        let vars = r#"{"won": true, "moves": 0, "level": "hard", "list": []}"#;
+        assert_eq!(render("{% if won %}yes{% else %}no{% endif %}", vars).unwrap(), "yes");
+        assert_eq!(render("{% if not won %}yes{% else %}no{% endif %}", vars).unwrap(), "no");
+        assert_eq!(render("{% if moves %}yes{% endif %}", vars).unwrap(), "");
+        assert_eq!(render("{% if list %}yes{% else %}empty{% endif %}", vars).unwrap(), "empty");
+        assert_eq!(render(r#"{% if level == "hard" %}hard{% endif %}"#, vars).unwrap(), "hard");
+        assert_eq!(render(r#"{% if level != "hard" %}easy{% endif %}"#, vars).unwrap(), "");
+        // A variable that isn't set is false, not an error
+        assert_eq!(render("{% if missing %}yes{% else %}no{% endif %}", vars).unwrap(), "no");
+    }
+
+    #[test]
+    fn loops() {
+        let vars = r#"{"rows": [["X", "O"], ["<", " "]]}"#;
+        let source = "{% for row in rows %}{{ loop.index }}:\
+                      {% for cell in row %}{{ cell }}{% if not loop.last %},{% endif %}{% endfor %}\
+                      {% if not loop.last %}|{% endif %}{% endfor %}";
+        assert_eq!(render(source, vars).unwrap(), "0:X,O|1:&lt;, ");
+        assert_eq!(render("{% for x in missing %}{{ x }}{% endfor %}", vars).unwrap(), "");
+        assert!(render("{% for x in rows.0.0 %}{% endfor %}", vars).is_err());
+    }
+
+    #[test]
+    fn missing_variables_are_errors() {
+        match render("<p>{{ user.name }}</p>", r#"{"user": {}}"#) {
+            Err(TemplateError::Render { ref template, ref message }) => {
+                assert_eq!(template, "test.html");
+                assert!(message.contains("user.name"), "{}", message);
+            }
+            other => panic!("{:?}", other),
+        }
+        // Objects and arrays can't be printed either
+        assert!(render("{{ user }}", r#"{"user": {}}"#).is_err());
+    }
+
+    #[test]
+    fn unclosed_tags() {
+        assert_eq!(syntax_line("one\ntwo {{ name"), 2);
+        assert_eq!(syntax_line("{% if won"), 1);
+        assert_eq!(syntax_line("{# comment"), 1);
+        assert_eq!(syntax_line("a\n{% if won %}\nyes"), 2);
+        assert_eq!(syntax_line("{% for x in xs %}"), 1);
+        assert_eq!(syntax_line("{% block content %}"), 1);
+        assert_eq!(syntax_line("{% if a %}{% else %}"), 1);
+        assert_eq!(syntax_line("text\n{% endif %}"), 2);
+        assert_eq!(syntax_line("{% nonsense %}"), 1);
+        assert_eq!(syntax_line("{{ name|upper }}"), 1);
+        assert_eq!(syntax_line("{{ bad-name }}"), 1);
+    }
+
+    #[test]
+    fn includes_and_layouts() {
+        let mut templates = Templates::new();
+        templates.add("layout.html", "<h1>{% block title %}Game{% endblock %}</h1>{% block body %}{% endblock %}").unwrap();
+        templates.add("cell.html", "[{{ cell }}]").unwrap();
+        templates.add("page.html", "{% extends \"layout.html\" %}\
+                                    {% block body %}{% for cell in cells %}{% include \"cell.html\" %}{% endfor %}{% endblock %}")
+            .unwrap();
+        templates.check().unwrap();
+        let vars = Json::from_str(r#"{"cells": ["X", "O"]}"#).unwrap();
+        assert_eq!(templates.render("page.html", &vars).unwrap(), "<h1>Game</h1>[X][O]");
+
+        templates.add("self.html", "{% include \"self.html\" %}").unwrap();
+        assert!(templates.render("self.html", &vars).is_err());
+        templates.add("broken.html", "{% include \"nowhere.html\" %}").unwrap();
+        match templates.check() {
+            Err(TemplateError::NotFound(ref name)) => assert_eq!(name, "nowhere.html"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(syntax_line("<p></p>{% extends \"layout.html\" %}"), 1);
+    }
+}
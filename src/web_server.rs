@@ -1,36 +1,44 @@
 extern crate rustc_serialize;
 extern crate common;
 
-use common::{Request, Response, UserData, Status};
-use rustc_serialize::json;
+mod api;
+mod game_client;
+mod live;
+mod session;
+
+use common::{Request, Response, UserData, Status, GameStatus, Difficulty, Opponent, Limits, ParseError,
+             Router, ThreadPool, Slots, BodyStream, StaticFiles, Templates, Cookie, BoardSize, Cell, EMPTY,
+             MAX_BOARD_SIDE};
+use common::protocol::{GameRequest, GameResponse, GameInfo, GameError, ErrorCode};
+use common::shutdown;
+use game_client::{GameClient, ClientError};
+use live::Live;
+use session::Sessions;
+use rustc_serialize::json::{self, Json, ToJson};
+use std::collections::BTreeMap;
 use std::env;
-use std::io::{Read, Write};
-use std::fs::File;
-use std::thread::spawn; // spawning threads
+use std::io::{BufReader, ErrorKind};
+use std::process;
+use std::sync::Arc;
 use std::net::{TcpListener, TcpStream, Shutdown};
+use std::time::Duration;
 
-/// Helper function for reading files, will return a 500 Status Response
+/// Helper function for rendering a page, will return a 500 Status Response
 /// which can be modified or sent to the client
 ///
-fn read_file(name: &str) -> Result<Vec<u8>, Response> {
-    let mut file = match File::open(name) {
-        Ok(o) => o,
-        Err(e) => {
-            let mut res = Status::internal_error();
-            res.body(format!("{:?} not found: {:?}", name, e).into_bytes());
-            return Err(res);
+#[allow(clippy::result_large_err)]
+fn render(templates: &Templates, name: &str, vars: BTreeMap<String, Json>) -> Result<Response, Response> {
+    match templates.render(name, &Json::Object(vars)) {
+        Ok(html) => {
+            let mut response = Status::ok();
+            response.body(html.into_bytes());
+            Ok(response)
         }
-    };
-    let mut buf = Vec::new();
-    match file.read_to_end(&mut buf) {
-        Ok(_) => {}
         Err(e) => {
-            let mut res = Status::internal_error();
-            res.body(format!("Error reading {:?}: {:?}", name, e).into_bytes());
-            return Err(res);
+            println!("Rendering {} failed: {}", name, e);
+            Err(Status::internal_error())
         }
     }
-    Ok(buf)
 }
 
 fn main() {
@@ -47,129 +55,557 @@ fn main() {
 
     // Start the listener on address provided
     let listener = TcpListener::bind(web_address).unwrap();
+    // The router is read only once built, so threads can share it via an Arc
+    // Templates are compiled once here, a broken one stops the server starting
+    let templates = match Templates::from_env() {
+        Ok(templates) => templates,
+        Err(e) => {
+            println!("Could not load templates: {}", e);
+            process::exit(1);
+        }
+    };
+    // As are static files from a directory that isn't there
+    let files = match StaticFiles::from_env() {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Could not serve static files: {}", e);
+            process::exit(1);
+        }
+    };
+    let context = Context {
+        client: GameClient::from_env(GAME_SERVER),
+        sessions: Sessions::from_env(),
+        templates,
+        live: Live::from_env(),
+    };
+    let router = Arc::new(routes(Arc::new(context), files));
+    // Connections are served by a fixed pool of worker threads, sized by
+    // the POOL_SIZE and POOL_QUEUE environment variables
+    let mut pool = ThreadPool::from_env();
+    // A connection kept open between requests holds its worker while idle,
+    // so one worker is always left for connections that aren't
+    let kept = Arc::new(Slots::new(pool.size() - 1));
+    // SIGINT or SIGTERM now end the loop below instead of killing the process
+    shutdown::install();
+    let incoming = match shutdown::incoming(&listener) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            println!("Could not set up listener: {:?}", e);
+            process::exit(1);
+        }
+    };
     // I originally was spawning a base thread that contained this loop
     // which would prevent blocking. But for this assignment it isn't really
     // required, and doing without makes the code a little cleaner.
-    // Not: The for loop for `.incoming()` is infinite.
-    for stream in listener.incoming().by_ref() {
+    // Note: The for loop runs until a shutdown is requested.
+    for stream in incoming {
         match stream {
             Ok(mut stream) => {
-                // For each incoming stream we spawn a thread using a closure.
-                // The keyword `move` shifts the `stream` in to the thread, i.e
-                // it takes ownership of the stream (connection).
-                spawn( move || {
-                    // Hand off a mutable reference to `parse_stream`, here we are
-                    // using the analogue of a C pointer.
-                    // & = reference, or "borrow" in Rust parlance
-                    let request = match Request::parse_stream(&mut stream) {
-                        // Pattern match the Result returned, this helps us prevent crashes,
-                        // panics, poisoning threads etc.
-                        Ok(request) => request,
-                        // If an error is encountered in the parsing we just print and
-                        // return from the spawned thread.
-                        Err(e) => {
-                            println!("Parsing stream to a request failed: {:?}", e);
-                            return;
-                        }
-                    };
-                    let response: Response;
-                    // The Request is parsed and jammed in to a Request data struct
-                    // A url can also contain a file name, eg index.html which can be
-                    // parsed easily anywhere desired, such as "/game/index.html".
-                    // I had initially created a hashmap of callbacks for this, which works
-                    // well for the task, but in the interest of being "to the point" didn't
-                    // use it.
-                    match request.url.as_ref() {
-                        // A basic router style match
-                        "/" =>      response = handle_new(&request),
-                        "/game/" => response = handle_tictac(&request),
-                        _ =>        response = Status::not_found(),
-                    }
-                    
-                    // For these two if statements we are only interested in whether or not
-                    // they were an Err Result. The content of the error could be extracted
-                    // using a `match` if desired.
-                    if stream.write_all(response.to_string().as_bytes()).is_err() {
+                // Every worker is busy and the queue is full, so turn the
+                // client away now rather than leave them waiting
+                if pool.is_full() {
+                    let mut response = Status::service_unavailable();
+                    response.header("Connection", "close");
+                    response.header("Retry-After", "1");
+                    if response.write_to(&mut stream).is_err() {
                         println!("Write to connection failed");
-                        return;
-                    }
-                    if stream.shutdown(Shutdown::Both).is_err() {
-                        println!("Could not shutdown stream correctly: prematurely closed?");
-                        return;
                     }
-                });
+                    continue;
+                }
+                // For each incoming stream we queue a job using a closure.
+                // The keyword `move` shifts the `stream` in to the job, i.e
+                // it takes ownership of the stream (connection).
+                let router = router.clone();
+                let kept = kept.clone();
+                if let Err(e) = pool.execute(move || handle_connection(stream, &router, &kept)) {
+                    println!("Could not queue connection: {:?}", e);
+                }
             }
             // Lastly, the initial connection attempt may have failed, so print and continue
             Err(e) => println!("Bad connection: {:?}", e),
         }
     }
+
+    // No new connections are accepted now, let the ones in progress finish
+    println!("Shutting down, waiting on open connections");
+    if !pool.shutdown_timeout(Duration::from_secs(SHUTDOWN_DEADLINE_SECS)) {
+        println!("Connections still open after {}s, exiting anyway", SHUTDOWN_DEADLINE_SECS);
+        process::exit(1);
+    }
 }
 
-/// Handle new users via url '/'
+/// How long open connections get to finish once a shutdown is requested
+const SHUTDOWN_DEADLINE_SECS: u64 = 10;
+
+/// How long a kept-alive connection may sit idle waiting for the next request
+const IDLE_TIMEOUT_SECS: u64 = 5;
+/// Requests served on one connection before it is closed
+const MAX_REQUESTS: u32 = 100;
+
+/// Serve every request sent on a connection, in order, until the client
+/// asks to close, goes idle, or `MAX_REQUESTS` is reached. The connection
+/// is only kept open if it gets one of the `kept` slots, otherwise it is
+/// closed after the first request.
 ///
-/// The Response here is a page that contains a button
-/// to start a new game - if there is no user_id in the
-/// request then one is randomly generated and inserted
-/// in to the response.
-fn handle_new(request: &Request) -> Response {
-    let ref user_id = match request.body.as_ref() { // Option<T>.as_ref()->Option<&T>
-        Some(map) => map.get("user_id").unwrap(),
-        None => "123",// TODO - fetch unused id from game server
+/// Pipelined requests are handled as the reader is buffered, any bytes read
+/// past the end of one request are kept for the next call to `read_from`.
+fn handle_connection(mut stream: TcpStream, router: &Router, kept: &Slots) {
+    let idle = Duration::from_secs(IDLE_TIMEOUT_SECS);
+    if stream.set_read_timeout(Some(idle)).is_err() {
+        println!("Could not set read timeout");
+        return;
+    }
+    // The read half gets its own handle so the BufReader can hold on to it
+    let mut reader = match stream.try_clone() {
+        Ok(read_half) => BufReader::new(read_half),
+        Err(e) => {
+            println!("Could not clone stream: {:?}", e);
+            return;
+        }
     };
-    // Create a response from a template, this returns a prebuilt Response struct
-    let mut response = Status::ok();
+    let limits = Limits::new();
+    // Held while the connection is kept open
+    let mut slot = None;
 
-    // As we saw at the top of the source, read_file() will return a prebuilt
-    // Response depending on its own results. We can just return this directly
-    // to the thread to use in -- "/" =>      response = handle_new(&request),
-    let index_file = match read_file("index.html") {
+    for served in 1..MAX_REQUESTS + 1 {
+        // Hand off a mutable reference to `read_from`, here we are
+        // using the analogue of a C pointer.
+        // & = reference, or "borrow" in Rust parlance
+        let mut request = match Request::read_from(&mut reader, &limits) {
+            // Pattern match the Result returned, this helps us prevent crashes,
+            // panics, poisoning threads etc.
+            Ok(request) => request,
+            // The client closed the connection or went idle between requests
+            Err(ParseError::Closed) => break,
+            Err(ParseError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock ||
+                                          e.kind() == ErrorKind::TimedOut => break,
+            // If an error is encountered in the parsing we print, send the
+            // matching error Response if the client is still there, and close.
+            Err(e) => {
+                println!("Parsing stream to a request failed: {:?}", e);
+                if let Some(mut response) = e.response() {
+                    if response.write_to(&mut stream).is_err() {
+                        println!("Write to connection failed");
+                    }
+                }
+                break;
+            }
+        };
+        // Connections are closed after the current request once a shutdown starts
+        let mut keep_alive = request.keep_alive() && served < MAX_REQUESTS && !shutdown::requested();
+
+        let mut response = router.dispatch(&mut request);
+        // HTTP/1.0 has no chunked encoding, so a stream of unknown length
+        // can only be ended by closing the connection
+        if request.version != "HTTP/1.1" {
+            if let Some(BodyStream { len: None, .. }) = response.stream {
+                keep_alive = false;
+            }
+        }
+        // An upgrade (eg to a WebSocket) sets its own Connection header, and
+        // ends the HTTP requests on this connection
+        let upgrade = response.upgrade.take();
+        if upgrade.is_some() {
+            keep_alive = false;
+        } else {
+            if keep_alive && slot.is_none() {
+                slot = kept.reserve();
+                keep_alive = slot.is_some();
+            }
+            if keep_alive {
+                response.header("Connection", "keep-alive");
+                let remaining = MAX_REQUESTS - served;
+                response.header("Keep-Alive", &format!("timeout={}, max={}", IDLE_TIMEOUT_SECS, remaining));
+            } else {
+                response.header("Connection", "close");
+            }
+        }
+        // We are only interested in whether or not this was an Err Result.
+        // The content of the error could be extracted using a `match` if desired.
+        // `write_to` sets the Content-Length so the client can tell where this
+        // response ends and the next begins.
+        if response.write_to(&mut stream).is_err() {
+            println!("Write to connection failed");
+            return;
+        }
+        // The client waits for the 101 before sending anything else, so
+        // nothing is left in `reader` and the connection can be handed over
+        if let Some(upgrade) = upgrade {
+            upgrade(stream);
+            return;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    if stream.shutdown(Shutdown::Both).is_err() {
+        println!("Could not shutdown stream correctly: prematurely closed?");
+    }
+}
+
+/// The table of urls served. The Request is parsed and jammed in to a
+/// Request data struct, then the router picks the handler by method and url.
+/// A url can also contain params, eg "/game/:id", which the router stores in
+/// `request.path_params` for the handler.
+fn routes(context: Arc<Context>, files: StaticFiles) -> Router {
+    let mut router = Router::new();
+    let ctx = context.clone();
+    router.get("/", move |request: &Request| handle_new(request, &ctx));
+    let ctx = context.clone();
+    router.get("/game/", move |request: &Request| handle_tictac(request, &ctx));
+    let ctx = context.clone();
+    router.post("/game/", move |request: &Request| handle_tictac(request, &ctx));
+    // Games against another person, the owner sends the /join link to a friend.
+    // "/game/invite" goes first so it isn't taken as a game id.
+    let ctx = context.clone();
+    router.post("/game/invite", move |request: &Request| handle_invite(request, &ctx));
+    let ctx = context.clone();
+    router.get("/game/:id", move |request: &Request| handle_view(request, &ctx));
+    let ctx = context.clone();
+    router.post("/game/:id", move |request: &Request| handle_tictac(request, &ctx));
+    let ctx = context.clone();
+    router.post("/game/:id/undo", move |request: &Request| handle_undo(request, &ctx));
+    // Pushes each change to the game to the page, see live.rs
+    let ctx = context.clone();
+    router.get("/game/:id/ws", move |request: &Request| live::handle_socket(request, &ctx));
+    // The same as server-sent events, for clients that can't use a WebSocket
+    let ctx = context.clone();
+    router.get("/game/:id/events", move |request: &Request| live::handle_events(request, &ctx));
+    let ctx = context.clone();
+    router.get("/join/:id", move |request: &Request| handle_join_page(request, &ctx));
+    let ctx = context.clone();
+    router.post("/join/:id", move |request: &Request| handle_join(request, &ctx));
+    // The JSON API, see api.rs
+    router.mount("/api", api::routes(context.clone()));
+    // css, scripts and images, from STATIC_ROOT under STATIC_PREFIX
+    println!("Serving {:?} at {}/", files.root, files.prefix);
+    let pattern = files.pattern();
+    router.get(&pattern, move |request: &Request| files.serve(request));
+    router
+}
+
+/// What the handlers share, built once in main
+struct Context {
+    client   : GameClient,
+    sessions : Sessions,
+    templates: Templates,
+    live     : Live,
+}
+
+/// Handle new users via url '/'
+///
+/// The Response here is a page that contains a button
+/// to start a new game - if the request has no valid session
+/// cookie then the game server allocates a user_id, and a
+/// cookie for it is set in the response.
+fn handle_new(request: &Request, ctx: &Context) -> Response {
+    let (user_id, cookie) = match session_user(request, ctx) {
         Ok(o) => o,
         Err(e) => return e,
     };
 
-    // Replace {user_id} as the vec<u8> is parsed to a String
-    // For extra safety, we do pattern matching and return a prebuilt
-    // Response if we ever encounter an error.
-    let body_work = match String::from_utf8(index_file) {
-        Ok(s) => s.replace("{user_id}", user_id),
-        // the `_` here is a "wildcard" match, it basically throws away the
-        // value contained in Err()
-        Err(_) => return Status::internal_error(),
-    };
+    // The page shows an empty board, each cell labelled with its move
+    let classic = BoardSize::classic();
+    let empty = vec![vec![EMPTY; classic.width as usize]; classic.height as usize];
+    let mut vars = BTreeMap::new();
+    vars.insert("user_id".to_string(), user_id.to_json());
+    vars.insert("rows".to_string(), board_rows(&empty));
+    vars.insert("difficulty".to_string(), Difficulty::Hard.as_str().to_json());
+    size_vars(&mut vars, classic);
 
-    // The body of the response stores a Vec<u8> type, so we need to convert
-    // the string to a vector array of u8 bytes.
-    response.body(body_work.into_bytes());
-    // And lastly, insert an extra header with the length of the body
-    let body_len = &response.body_len().to_string();
-    response.header("Content-Length", body_len);
+    // As we saw at the top of the source, render() will return a prebuilt
+    // Response depending on its own results. We can just return this directly
+    match render(&ctx.templates, "index.html", vars) {
+        Ok(o) => with_cookie(o, cookie),
+        Err(e) => e,
+    }
+}
+
+/// The user in the session cookie, or a new user from the game_server
+/// along with the cookie to send them if there was no session
+///
+#[allow(clippy::result_large_err)]
+fn session_user(request: &Request, ctx: &Context) -> Result<(u32, Option<Cookie>), Response> {
+    match ctx.sessions.user_id(request) {
+        Some(id) => Ok((id, None)),
+        None => {
+            let id = new_user(&ctx.client)?;
+            Ok((id, Some(ctx.sessions.cookie(id))))
+        }
+    }
+}
+
+/// Add the session cookie from `session_user` to a response, if one is needed
+///
+fn with_cookie(mut response: Response, cookie: Option<Cookie>) -> Response {
+    if let Some(cookie) = cookie {
+        if let Err(e) = response.set_cookie(cookie) {
+            println!("{}", e);
+            return Status::internal_error();
+        }
+    }
     response
 }
 
+/// The numeric `:id` from the url, anything else can't be a game so is a 404
+///
+#[allow(clippy::result_large_err)]
+fn game_id_param(request: &Request) -> Result<Option<u32>, Response> {
+    match request.path_params.get("id") {
+        Some(id) => match id.parse::<u32>() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => Err(Status::not_found()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The user must already have a session to see or play a game
+///
+#[allow(clippy::result_large_err)]
+fn require_session(request: &Request, ctx: &Context) -> Result<u32, Response> {
+    match ctx.sessions.user_id(request) {
+        Some(id) => Ok(id),
+        None => {
+            let mut response = Status::forbidden();
+            response.body("No session, please start a game from /".as_bytes().to_vec());
+            Err(response)
+        }
+    }
+}
+
 /// The main handler for client games.
 ///
 /// This function fetches values from the parsed request and
 /// serializes them to JSON for transmission to the game_server.
-/// Upon recieving a response it then deserializes, and parses
-/// to an html table string for insertion in to the html string.
+/// Upon recieving a response it then deserializes, and renders
+/// the board in to the game page.
+///
+fn handle_tictac(request: &Request, ctx: &Context) -> Response {
+    // The user is who the session cookie says, never what the request says
+    let user_id = match require_session(request, ctx) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    // Posted to "/game/:id" for a particular game, or to "/game/" for the
+    // users current game
+    let game_id = match game_id_param(request) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    // A JSON body is answered with the game as JSON, so scripts can play
+    // through the same url as the html form
+    let json_api = request.is_json();
+    let user_data = if json_api {
+        match request.decode_body::<UserData>() {
+            Ok(data) => data,
+            Err(e) => return e,
+        }
+    } else {
+        match form_user_data(request) {
+            Ok(data) => data,
+            Err(e) => return e,
+        }
+    };
+
+    let game = match play(user_id, game_id, &user_data, &ctx.client) {
+        Ok(game) => game,
+        Err(e) => {
+            if let Some(game_id) = game_id {
+                ctx.live.check_removed(game_id, user_id, &e);
+            }
+            return e;
+        }
+    };
+    ctx.live.publish(&game);
+
+    if json_api {
+        let mut response = Status::ok();
+        response.header("Content-Type", "application/json");
+        match json::encode(&game.grid) {
+            Ok(o) => response.body(o.into_bytes()),
+            Err(_) => return Status::internal_error(),
+        }
+        return response;
+    }
+
+    render_game(ctx, user_id, &game)
+}
+
+/// Show a game via url '/game/:id', to either of its players. A player
+/// waiting on the other reloads this to see their move.
+///
+fn handle_view(request: &Request, ctx: &Context) -> Response {
+    let user_id = match require_session(request, ctx) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    let game_id = match game_id_param(request) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    match get_game(user_id, game_id, &ctx.client) {
+        Ok(game) => render_game(ctx, user_id, &game),
+        Err(e) => {
+            if let Some(game_id) = game_id {
+                ctx.live.check_removed(game_id, user_id, &e);
+            }
+            e
+        }
+    }
+}
+
+/// Take back the user's last move and the computer's reply via a POST to
+/// '/game/:id/undo', then go back to the game's page
 ///
-fn handle_tictac(request: &Request) -> Response {
+fn handle_undo(request: &Request, ctx: &Context) -> Response {
+    let user_id = match require_session(request, ctx) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    let game_id = match game_id_param(request) {
+        Ok(Some(id)) => id,
+        Ok(None) | Err(_) => return Status::not_found(),
+    };
+    match call_game(&ctx.client, GameRequest::Undo { user_id, game_id: Some(game_id) }) {
+        Ok(game) => ctx.live.publish(&game),
+        Err(e) => {
+            ctx.live.check_removed(game_id, user_id, &e);
+            return e;
+        }
+    }
+    let mut response = Status::see_other();
+    response.header("Location", &format!("/game/{}", game_id));
+    response
+}
+
+/// Start a game against another person via url '/game/invite'. The game
+/// waits for someone to join, its page shows the link to send them.
+///
+fn handle_invite(request: &Request, ctx: &Context) -> Response {
+    let (user_id, cookie) = match session_user(request, ctx) {
+        Ok(o) => o,
+        Err(e) => return e,
+    };
+    let difficulty = match request.get_param("difficulty") {
+        Ok(d) => Difficulty::from_name(d),
+        Err(_) => Difficulty::Hard,
+    };
+    let new_game = GameRequest::NewGame {
+        user_id,
+        difficulty,
+        opponent: Opponent::Human,
+        size: form_board_size(request),
+    };
+    let game = match call_game(&ctx.client, new_game) {
+        Ok(game) => game,
+        Err(e) => return e,
+    };
+    let mut response = Status::see_other();
+    response.header("Location", &format!("/game/{}", game.id));
+    with_cookie(response, cookie)
+}
+
+/// The page an invite link leads to, via url '/join/:id', with a button to
+/// take the second seat. A visitor without a session is given one here.
+///
+fn handle_join_page(request: &Request, ctx: &Context) -> Response {
+    let game_id = match game_id_param(request) {
+        Ok(Some(id)) => id,
+        Ok(None) | Err(_) => return Status::not_found(),
+    };
+    let (user_id, cookie) = match session_user(request, ctx) {
+        Ok(o) => o,
+        Err(e) => return e,
+    };
+    let mut vars = BTreeMap::new();
+    vars.insert("user_id".to_string(), user_id.to_json());
+    vars.insert("game_id".to_string(), game_id.to_json());
+    vars.insert("action".to_string(), format!("/join/{}", game_id).to_json());
+    match render(&ctx.templates, "join.html", vars) {
+        Ok(o) => with_cookie(o, cookie),
+        Err(e) => e,
+    }
+}
+
+/// Take the second seat of a game via a POST to '/join/:id', then go on to
+/// the game's page
+///
+fn handle_join(request: &Request, ctx: &Context) -> Response {
+    let game_id = match game_id_param(request) {
+        Ok(Some(id)) => id,
+        Ok(None) | Err(_) => return Status::not_found(),
+    };
+    let (user_id, cookie) = match session_user(request, ctx) {
+        Ok(o) => o,
+        Err(e) => return e,
+    };
+    match call_game(&ctx.client, GameRequest::JoinGame { user_id, game_id }) {
+        Ok(game) => ctx.live.publish(&game),
+        Err(e) => return e,
+    }
+    let mut response = Status::see_other();
+    response.header("Location", &format!("/game/{}", game_id));
+    with_cookie(response, cookie)
+}
+
+/// Render the page for a game as `user_id` sees it. A finished game gets
+/// the end page instead, which has no move form.
+///
+fn render_game(ctx: &Context, user_id: u32, game: &GameInfo) -> Response {
+    let page = if game.grid.status.is_over() { "end.html" } else { "game.html" };
+    let seat = game.seat(user_id);
+    let mut vars = BTreeMap::new();
+    vars.insert("user_id".to_string(), user_id.to_json());
+    vars.insert("status".to_string(), status_message(game, user_id).to_json());
+    vars.insert("rows".to_string(), board_rows(&game.grid.data));
+    vars.insert("difficulty".to_string(), game.difficulty.as_str().to_json());
+    size_vars(&mut vars, BoardSize { width: game.grid.width(), height: game.grid.height(), win: game.grid.win });
+    vars.insert("action".to_string(), format!("/game/{}", game.id).to_json());
+    let can_move = seat.is_some() && game.turn == seat;
+    vars.insert("can_move".to_string(), can_move.to_json());
+    // Against the computer the user can take a move back once they've made one
+    let played = game.grid.data.iter().flat_map(|row| row.iter()).any(|&cell| cell == 'X');
+    vars.insert("can_undo".to_string(), (can_move && played && game.opponent == Opponent::Computer).to_json());
+    // What live.js compares the pushed state with, the cells then the
+    // status as it is named in JSON
+    let cells: String = game.grid.data.iter().flat_map(|row| row.iter()).collect();
+    vars.insert("state".to_string(), format!("{}{:?}", cells, game.grid.status).to_json());
+    // Only the owner has anyone to invite, and only until they join
+    if game.grid.status == GameStatus::Waiting && game.owner == user_id {
+        vars.insert("invite".to_string(), format!("/join/{}", game.id).to_json());
+    }
+
+    // Remember the render helper function returns a Response to use if Err()
+    match render(&ctx.templates, page, vars) {
+        Ok(o) => o,
+        Err(e) => e,
+    }
+}
+
+/// Fill a UserData from the params posted by the html form
+///
+#[allow(clippy::result_large_err)]
+fn form_user_data(request: &Request) -> Result<UserData, Response> {
     // Request body is optional, need to check it exists first
     // the .get_param() return type is Result<&String, Response>
     // We get either an Ok(&String) or an Err(Response)
     // Of note here is we get a reference to a string, or we get a
     // Response `moved` to here, i.e, take ownership of that data,
     // and ownership moves upwards with each return
-    let user_id = match request.get_param("user_id") {
-        Ok(user) => user,
-        Err(e) => return e,
-    };
     let move_to = match request.get_param("move_to") {
-        Ok(mv) => mv.as_bytes()[0] as char,
-        Err(_) => 'n',
+        Ok(mv) => mv.trim().to_string(),
+        Err(_) => String::new(),
     };
-    // We can get around this typing stuff by using an enum storage for the body hashmap
-    // ... Maybe later
+    // This was an attempt to get the game server to accept codes, and it does work,
+    // it just wasn't suitable here. Instead we're returning a Response with a message.
+    // This could easily be crafted to show the current game + a message somewhere
+    // on the page
+    if Cell::parse(&move_to).is_none() {
+        let mut response = Status::ok();
+        response.body("Illegal move, please press back".as_bytes().to_vec());
+        return Err(response);
+    }
     let new_game = match request.get_param("new_game") {
         Ok(ng) => {
             match ng.as_str() {
@@ -180,122 +616,326 @@ fn handle_tictac(request: &Request) -> Response {
         }
         Err(_) => true, // Maybe shouldn't ignore the error, but the other fields are fine
     };
+    // Only used by the game server when starting a new game
+    let difficulty = match request.get_param("difficulty") {
+        Ok(d) => Difficulty::from_name(d),
+        Err(_) => Difficulty::Hard,
+    };
 
     // Create the filled struct using the above variables
-    let user_data = UserData {
-        user_id: user_id.parse::<u32>().unwrap(),
-        move_to: move_to,
-        new_game: new_game,
-    };
-    // Create the JSON string to send to the game server
-    let user_json = match json::encode(&user_data) {
-        Err(e) => format!("JSON conversion failed: {:?}", e),
-        Ok(string) => {
-            // This was an attempt to get the game server to accept codes, and it does work,
-            // it just wasn't suitable here. Instead we're returning a Response with a message.
-            // This could easily be crafted to show the current game + a message somewhere
-            // on the page
-            let s = match move_to {
-                '0' => "0:".to_string() + &string,
-                '1' => "0:".to_string() + &string,
-                '2' => "0:".to_string() + &string,
-                '3' => "0:".to_string() + &string,
-                '4' => "0:".to_string() + &string,
-                '5' => "0:".to_string() + &string,
-                '6' => "0:".to_string() + &string,
-                '7' => "0:".to_string() + &string,
-                '8' => "0:".to_string() + &string,
-                _ => { //"1:".to_string() + &string,
-                    let mut response = Status::ok();
-                    response.body("Illegal move, please press back".as_bytes().to_vec());
-                    let body_len = &response.body_len().to_string();
-                    response.header("Content-Length", body_len);
-                    return response;
-                }
-            };
-            s
-        }
+    Ok(UserData {
+        move_to,
+        new_game,
+        difficulty,
+        size: Some(form_board_size(request)),
+    })
+}
+
+/// The board posted by a new game form, any field left out (or that
+/// isn't a number) is as in plain tic tac toe
+///
+fn form_board_size(request: &Request) -> BoardSize {
+    let classic = BoardSize::classic();
+    let field = |name: &str, default: u32| match request.get_param(name) {
+        Ok(val) => val.trim().parse().unwrap_or(default),
+        Err(_) => default,
     };
-    println!("JSON = {:?}", user_json);
-
-    // Send JSON to game_server and parse received JSON to data structure (vec)
-    // using the helper function
-    let game = match rw_user_data(&user_json, "localhost:3001") {
-        Ok(game) => {
-            // NOTE: Any place with a `.unwrap()` is a potential crash, this should be replaced
-            // with the right handling such as the `match` statements seen so far.
-            // The `.unwrap` just yanks the Ok/Some variable out and panics! if Err/None
-            // it /can/ be okay iff we know for sure it is safe.
-            match json::decode(&String::from_utf8(game).unwrap()) {
-                Err(_) => return Status::internal_error(),
-                Ok(o) => o,
-            }
-        }
-        Err(_) => return Status::internal_error(),
+    BoardSize {
+        width: field("width", classic.width),
+        height: field("height", classic.height),
+        win: field("win", classic.win),
+    }
+}
+
+/// Send a move (or new game) to the game_server and return the game it
+/// replies with. A new game is always against the computer, and the move is
+/// made in it rather than in `game_id`.
+///
+#[allow(clippy::result_large_err)]
+fn play(user_id: u32, game_id: Option<u32>, user_data: &UserData, client: &GameClient)
+        -> Result<GameInfo, Response> {
+    let cell = match Cell::parse(&user_data.move_to) {
+        Some(cell) => cell,
+        None => return Err(Status::faulty_query("The move must be a cell number or \"row,col\"")),
     };
+    let mut requests = Vec::new();
+    let mut game_id = game_id;
+    if user_data.new_game {
+        requests.push(GameRequest::NewGame {
+            user_id,
+            difficulty: user_data.difficulty,
+            opponent: Opponent::Computer,
+            size: user_data.size.unwrap_or_else(BoardSize::classic),
+        });
+        game_id = None;
+    }
+    requests.push(GameRequest::MakeMove { user_id, game_id, cell });
 
-    // Remember the read_file helper function returns a Response to use if Err()
-    let game_file = match read_file("game.html") {
-        Ok(o) => o,
-        Err(e) => return e,
+    // Send the requests to game_server over one connection, it is the reply
+    // to the move that holds the board we want
+    let reply = match client.call(&requests) {
+        Ok(mut replies) => replies.pop(),
+        Err(e) => return Err(client_error_response(e)),
     };
+    match reply {
+        Some(GameResponse::Game(game)) => Ok(game),
+        // A move that can't be played leaves the board as it was, so show that
+        Some(GameResponse::Error(ref e)) if e.code == ErrorCode::IllegalMove ||
+                                            e.code == ErrorCode::GameOver ||
+                                            e.code == ErrorCode::NotYourTurn => {
+            println!("User {:?}: {:?}", user_id, e);
+            get_game(user_id, game_id, client)
+        }
+        Some(GameResponse::Error(e)) => Err(game_error_response(&e)),
+        _ => Err(Status::internal_error()),
+    }
+}
 
-    // Create the html table using the helper function
-    let game_table = create_table(game);
-
-    // Start crafting a new response using the ok() preset
-    let mut response = Status::ok();
-    // Chain the `.replace`, this string function is a copy iterator over the string, which
-    // replaces any pattern encountered as it does so
-    let body_work = String::from_utf8(game_file)
-        .unwrap() // potentially a crash spot
-        .replace("{user_id}", user_id)
-        .replace("{game_table}", &game_table);
-    // Insert our new body in to the response. The body is
-    // a Vec<u8> so transform the string in to a vector of bytes.
-    response.body(body_work.into_bytes()); 
-
-    let body_len = &response.body_len().to_string();
-    response.header("Content-Length", body_len);
-    response
+/// Fetch a game of the user's, or their current game if `game_id` is None
+///
+#[allow(clippy::result_large_err)]
+fn get_game(user_id: u32, game_id: Option<u32>, client: &GameClient) -> Result<GameInfo, Response> {
+    call_game(client, GameRequest::GetGame { user_id, game_id })
 }
 
-/// Helper function to write to the game_server and listen to output
+/// Send one request to the game_server, expecting a game back
 ///
-/// The return type is a Result - Result<Vec<u8>, String>
+#[allow(clippy::result_large_err)]
+fn call_game(client: &GameClient, request: GameRequest) -> Result<GameInfo, Response> {
+    match client.call(&[request]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::Game(game)) => Ok(game),
+            Some(GameResponse::Error(e)) => Err(game_error_response(&e)),
+            _ => Err(Status::internal_error()),
+        },
+        Err(e) => Err(client_error_response(e)),
+    }
+}
+
+/// Ask the game_server for a new user id, for a visitor without a session
 ///
-fn rw_user_data(user_data: &str, addr: &str) -> Result<Vec<u8>, String> {
-    let mut game: Vec<u8> = Vec::new();
-    // Pattern matching to try and safely handle all possible results.
-    match TcpStream::connect(addr) {
-        Ok(ref mut o) => {
-            if o.write_all(user_data.as_bytes()).is_err() {
-                return Err(format!("Could not write to {:?}", addr));
-            }
-            if o.shutdown(Shutdown::Write).is_err() {
-                return Err(format!("Stream to {:?} ended prematurely?", addr));
-            }
-            if o.read_to_end(&mut game).is_err() {
-                return Err(format!("Could not read from {:?}", addr));
-            }
-            return Ok(game);
+#[allow(clippy::result_large_err)]
+fn new_user(client: &GameClient) -> Result<u32, Response> {
+    match client.call(&[GameRequest::NewUser]) {
+        Ok(mut replies) => match replies.pop() {
+            Some(GameResponse::User(id)) => Ok(id),
+            Some(GameResponse::Error(e)) => Err(game_error_response(&e)),
+            _ => Err(Status::internal_error()),
+        },
+        Err(e) => Err(client_error_response(e)),
+    }
+}
+
+/// Where the game_server listens
+const GAME_SERVER: &str = "localhost:3001";
+
+/// The Response to send for an error from the game_server
+///
+fn game_error_response(error: &GameError) -> Response {
+    println!("Game server error: {:?}", error);
+    match error.code {
+        ErrorCode::NoSuchGame => Status::not_found(),
+        ErrorCode::IllegalMove | ErrorCode::InvalidBoard => Status::faulty_query(&error.message),
+        ErrorCode::GameOver | ErrorCode::NotYourTurn | ErrorCode::NotJoinable | ErrorCode::CannotUndo => {
+            let mut response = Status::conflict();
+            response.body(error.message.as_bytes().to_vec());
+            response
         }
-        Err(e) => return Err(format!("Game server down? {:?}", e)),
-    };
+        ErrorCode::Busy => Status::service_unavailable(),
+        _ => Status::internal_error(),
+    }
+}
+
+/// The game_server couldn't be reached in time, tell the client to try again
+///
+fn client_error_response(error: ClientError) -> Response {
+    match error {
+        ClientError::Unavailable(msg) => println!("Game server unavailable: {}", msg),
+        ClientError::Io(msg) => println!("Game server connection failed: {}", msg),
+    }
+    let mut response = Status::service_unavailable();
+    response.header("Retry-After", "1");
+    response
+}
+
+/// The board as rows of cell labels, for the board.html template to
+/// turn in to a table
+///
+fn board_rows(board: &[Vec<char>]) -> Json {
+    cell_labels(board).to_json()
 }
 
-/// A simple iterator over the game data to produce an HTML table
+/// The board with each piece as "X" or "O", and each free cell labelled
+/// with its number, which is what is typed to play it
 ///
-fn create_table(game: Vec<Vec<char>>) -> String {
-    let mut game_table = String::new();
-    for row in game {
-        game_table.push_str("<tr>");
-        for col in row {
-            game_table.push_str("<td>[");
-            game_table.push(col);
-            game_table.push_str("]</td>");
+fn cell_labels(board: &[Vec<char>]) -> Vec<Vec<String>> {
+    board.iter().enumerate()
+        .map(|(y, row)| row.iter().enumerate()
+            .map(|(x, &cell)| match cell {
+                EMPTY => (y * row.len() + x).to_string(),
+                piece => piece.to_string(),
+            })
+            .collect())
+        .collect()
+}
+
+/// The width, height and win of a board, for the new game form
+/// (size.html) to start from and to describe the game being played
+///
+fn size_vars(vars: &mut BTreeMap<String, Json>, size: BoardSize) {
+    vars.insert("width".to_string(), size.width.to_json());
+    vars.insert("height".to_string(), size.height.to_json());
+    vars.insert("win".to_string(), size.win.to_json());
+    vars.insert("max_side".to_string(), MAX_BOARD_SIDE.to_json());
+}
+
+/// The line of text shown to `user_id` for the state of a game
+///
+fn status_message(game: &GameInfo, user_id: u32) -> &'static str {
+    let seat = game.seat(user_id);
+    let winner = match game.grid.status {
+        GameStatus::Waiting => return "Waiting for someone to join",
+        GameStatus::InProgress if game.turn == seat => return "Your move",
+        GameStatus::InProgress => return "Waiting for your opponent's move",
+        GameStatus::Draw => return "It's a draw!",
+        GameStatus::XWon => 'X',
+        GameStatus::OWon => 'O',
+    };
+    if seat == Some(winner) {
+        "You win!"
+    } else if game.opponent == Opponent::Computer {
+        "The computer wins!"
+    } else {
+        "Your opponent wins!"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread::{self, JoinHandle};
+
+    /// Answers `/echo/:word` with the word
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/echo/:word", |request: &Request| {
+            let mut response = Status::ok();
+            response.body(request.path_params["word"].as_bytes().to_vec());
+            response
+        });
+        router
+    }
+
+    /// A connection to a thread serving it with `handle_connection`
+    fn connect(kept: &Arc<Slots>) -> (TcpStream, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let kept = kept.clone();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &router(), &kept);
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    fn get(word: &str, close: bool) -> String {
+        let connection = if close { "Connection: close\r\n" } else { "" };
+        format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", word, connection)
+    }
+
+    /// Read one response, which has a Content-Length
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut out = Vec::new();
+        let mut byte = [0u8; 1];
+        while !out.ends_with(b"\r\n\r\n") {
+            assert_eq!(stream.read(&mut byte).unwrap(), 1, "Connection closed in the headers");
+            out.push(byte[0]);
         }
-        game_table.push_str("</tr>");
+        let head = String::from_utf8(out).unwrap();
+        let len: usize = head.lines()
+            .find(|line| line.starts_with("Content-Length: "))
+            .map(|line| line["Content-Length: ".len()..].parse().unwrap())
+            .unwrap();
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).unwrap();
+        head + &String::from_utf8(body).unwrap()
+    }
+
+    /// Whether the server has closed the connection
+    fn closed(stream: &mut TcpStream) -> bool {
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        rest.is_empty()
+    }
+
+    #[test]
+    fn pipelined_requests_answered_in_order() {
+        let kept = Arc::new(Slots::new(4));
+        let (mut stream, server) = connect(&kept);
+        // All sent before any response is read
+        let requests = get("one", false) + &get("two", false) + &get("three", true);
+        stream.write_all(requests.as_bytes()).unwrap();
+        let first = read_response(&mut stream);
+        assert!(first.starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(first.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(first.contains(&format!("\r\nKeep-Alive: timeout={}, max={}\r\n", IDLE_TIMEOUT_SECS, MAX_REQUESTS - 1)));
+        assert!(first.ends_with("\r\n\r\none"));
+        let second = read_response(&mut stream);
+        assert!(second.contains(&format!("max={}\r\n", MAX_REQUESTS - 2)));
+        assert!(second.ends_with("\r\n\r\ntwo"));
+        let third = read_response(&mut stream);
+        assert!(third.contains("\r\nConnection: close\r\n"));
+        assert!(third.ends_with("\r\n\r\nthree"));
+        assert!(closed(&mut stream));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connection_close_ends_the_connection() {
+        let kept = Arc::new(Slots::new(4));
+        let (mut stream, server) = connect(&kept);
+        // The request after the close is never answered
+        stream.write_all((get("one", true) + &get("two", false)).as_bytes()).unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(!response.contains("Keep-Alive"));
+        assert!(response.ends_with("one"));
+        assert!(closed(&mut stream));
+        server.join().unwrap();
+
+        // HTTP/1.0 closes unless asked not to
+        let (mut stream, server) = connect(&kept);
+        stream.write_all(b"GET /echo/old HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("\r\nConnection: close\r\n"));
+        assert!(closed(&mut stream));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn kept_connections_are_capped() {
+        let kept = Arc::new(Slots::new(1));
+        let (mut first, first_server) = connect(&kept);
+        first.write_all(get("one", false).as_bytes()).unwrap();
+        assert!(read_response(&mut first).contains("\r\nConnection: keep-alive\r\n"));
+
+        // With the only slot taken, another connection is closed after its
+        // request rather than holding a worker while idle
+        let (mut second, second_server) = connect(&kept);
+        second.write_all(get("two", false).as_bytes()).unwrap();
+        assert!(read_response(&mut second).contains("\r\nConnection: close\r\n"));
+        assert!(closed(&mut second));
+        second_server.join().unwrap();
+
+        // The first stays open, until it closes and frees the slot
+        first.write_all(get("three", false).as_bytes()).unwrap();
+        assert!(read_response(&mut first).contains("\r\nConnection: keep-alive\r\n"));
+        drop(first);
+        first_server.join().unwrap();
+        let (mut third, third_server) = connect(&kept);
+        third.write_all(get("four", false).as_bytes()).unwrap();
+        assert!(read_response(&mut third).contains("\r\nConnection: keep-alive\r\n"));
+        drop(third);
+        third_server.join().unwrap();
     }
-    game_table
 }
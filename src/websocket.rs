@@ -0,0 +1,280 @@
+//! The server side of the WebSocket protocol (RFC 6455)
+//!
+//! `handshake` checks an upgrade request and builds the 101 response for
+//! it, after which the connection carries frames instead of HTTP. Messages
+//! from the client are read with a `MessageReader`, which joins fragmented
+//! messages and unmasks them, and messages are sent with `write_message`.
+//! Frames from the server are never masked or fragmented.
+//!
+//! example:
+//!    let mut response = websocket::handshake(request)?;
+//!    response.upgrade(move |stream| {
+//!        let mut reader = MessageReader::new(stream.try_clone().unwrap(), 4096);
+//!        while let Ok(Message::Text(text)) = reader.read_message() {
+//!            websocket::write_message(&mut &stream, &Message::Text(text)).unwrap();
+//!        }
+//!    });
+//!
+
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use std::io::{self, Read, Write};
+use std::str;
+use crypto::sha1;
+use {Request, Response, Status};
+
+/// Appended to the client's key before hashing, fixed by the RFC
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Check `request` is a WebSocket upgrade and answer it with a 101. A
+/// request that isn't gets a 400, or a 426 if it asks for a version other
+/// than 13, the only one there is.
+#[allow(clippy::result_large_err)]
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    let has_token = |name: &str, token: &str| match request.get_header(name) {
+        Some(val) => val.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    };
+    if request.method != "GET" || request.version != "HTTP/1.1" ||
+       !has_token("Connection", "upgrade") || !has_token("Upgrade", "websocket") {
+        return Err(bad_handshake("Not a WebSocket upgrade"));
+    }
+    if request.get_header("Sec-WebSocket-Version").map(|v| v.trim()) != Some("13") {
+        let mut response = Status::upgrade_required();
+        response.header("Sec-WebSocket-Version", "13");
+        return Err(response);
+    }
+    // The key is 16 random bytes, base64 encoded
+    let key = match request.get_header("Sec-WebSocket-Key") {
+        Some(key) => key.trim(),
+        None => return Err(bad_handshake("Missing Sec-WebSocket-Key")),
+    };
+    match key.from_base64() {
+        Ok(ref bytes) if bytes.len() == 16 => {}
+        _ => return Err(bad_handshake("Invalid Sec-WebSocket-Key")),
+    }
+
+    let mut response = Status::switching_protocols();
+    response.header("Upgrade", "websocket");
+    response.header("Connection", "Upgrade");
+    response.header("Sec-WebSocket-Accept", &accept_key(key));
+    Ok(response)
+}
+
+/// The `Sec-WebSocket-Accept` for a `Sec-WebSocket-Key`, proving to the
+/// client that the server understood the handshake
+pub fn accept_key(key: &str) -> String {
+    sha1((key.to_string() + GUID).as_bytes()).to_base64(STANDARD)
+}
+
+fn bad_handshake(text: &str) -> Response {
+    let mut response = Status::bad_request();
+    response.body(text.as_bytes().to_vec());
+    response
+}
+
+/// A whole message, after any fragments have been joined
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code the closing side gave, if any
+    Close(Option<u16>),
+}
+
+/// Why a message could not be read. Apart from `Io`, the connection should
+/// be closed with the error's `close_code`.
+#[derive(Debug)]
+pub enum WsError {
+    Io(io::Error),
+    /// The frames broke the protocol, eg an unmasked frame from a client
+    Protocol(&'static str),
+    /// A text message that isn't UTF-8
+    InvalidText,
+    /// A message longer than the reader allows
+    TooBig,
+}
+impl WsError {
+    /// The status code to close the connection with (RFC 6455 7.4.1)
+    pub fn close_code(&self) -> u16 {
+        match *self {
+            WsError::Io(_) => 1006,
+            WsError::Protocol(_) => 1002,
+            WsError::InvalidText => 1007,
+            WsError::TooBig => 1009,
+        }
+    }
+}
+impl From<io::Error> for WsError {
+    fn from(e: io::Error) -> WsError {
+        WsError::Io(e)
+    }
+}
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Reads messages sent by a client
+pub struct MessageReader<R> {
+    inner  : R,
+    /// The longest message accepted, in bytes
+    max_len: usize,
+    /// The opcode and payload so far of a fragmented message
+    partial: Option<(u8, Vec<u8>)>,
+}
+impl<R: Read> MessageReader<R> {
+    pub fn new(inner: R, max_len: usize) -> MessageReader<R> {
+        MessageReader { inner, max_len, partial: None }
+    }
+
+    /// Read the next whole message. Control messages (ping, pong and close)
+    /// may arrive between the fragments of another message, they are
+    /// returned as they come and the other message is kept for later.
+    pub fn read_message(&mut self) -> Result<Message, WsError> {
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+            match opcode {
+                CLOSE => {
+                    let code = match payload.len() {
+                        0 => None,
+                        1 => return Err(WsError::Protocol("Close frame with a 1 byte payload")),
+                        _ => Some((payload[0] as u16) << 8 | payload[1] as u16),
+                    };
+                    if str::from_utf8(&payload[payload.len().min(2)..]).is_err() {
+                        return Err(WsError::InvalidText);
+                    }
+                    return Ok(Message::Close(code));
+                }
+                PING => return Ok(Message::Ping(payload)),
+                PONG => return Ok(Message::Pong(payload)),
+                TEXT | BINARY => {
+                    if self.partial.is_some() {
+                        return Err(WsError::Protocol("New message before the last one finished"));
+                    }
+                    if fin {
+                        return to_message(opcode, payload);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+                CONTINUATION => {
+                    let (first, mut data) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => return Err(WsError::Protocol("Continuation with no message to continue")),
+                    };
+                    if data.len() + payload.len() > self.max_len {
+                        return Err(WsError::TooBig);
+                    }
+                    data.extend_from_slice(&payload);
+                    if fin {
+                        return to_message(first, data);
+                    }
+                    self.partial = Some((first, data));
+                }
+                _ => return Err(WsError::Protocol("Unknown opcode")),
+            }
+        }
+    }
+
+    /// Read one frame, returning whether it is the final fragment, its
+    /// opcode and its unmasked payload
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), WsError> {
+        let mut head = [0u8; 2];
+        self.inner.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        // No extensions are agreed in the handshake, so the bits they use must be clear
+        if head[0] & 0x70 != 0 {
+            return Err(WsError::Protocol("Reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(WsError::Protocol("Frames from a client must be masked"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut buf = [0u8; 2];
+                self.inner.read_exact(&mut buf)?;
+                (buf[0] as u64) << 8 | buf[1] as u64
+            }
+            127 => {
+                let mut buf = [0u8; 8];
+                self.inner.read_exact(&mut buf)?;
+                buf.iter().fold(0u64, |len, &b| len << 8 | b as u64)
+            }
+            n => n as u64,
+        };
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(WsError::Protocol("Control frames must be short and not fragmented"));
+        }
+        if len > self.max_len as u64 {
+            return Err(WsError::TooBig);
+        }
+        let mut mask = [0u8; 4];
+        self.inner.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        self.inner.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+fn to_message(opcode: u8, payload: Vec<u8>) -> Result<Message, WsError> {
+    if opcode == BINARY {
+        return Ok(Message::Binary(payload));
+    }
+    match String::from_utf8(payload) {
+        Ok(text) => Ok(Message::Text(text)),
+        Err(_) => Err(WsError::InvalidText),
+    }
+}
+
+/// Send a message as a single unmasked frame
+pub fn write_message<W: Write>(out: &mut W, message: &Message) -> io::Result<()> {
+    let close;
+    let (opcode, payload): (u8, &[u8]) = match *message {
+        Message::Text(ref text) => (TEXT, text.as_bytes()),
+        Message::Binary(ref data) => (BINARY, data),
+        Message::Ping(ref data) => (PING, data),
+        Message::Pong(ref data) => (PONG, data),
+        Message::Close(code) => {
+            close = match code {
+                Some(code) => vec![(code >> 8) as u8, code as u8],
+                None => Vec::new(),
+            };
+            (CLOSE, &close)
+        }
+    };
+    let mut frame = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        frame.push(127);
+        for i in (0..8).rev() {
+            frame.push((len as u64 >> (i * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from RFC 6455 section 1.3
+    #[test]
+    fn accept_key_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}
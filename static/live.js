@@ -0,0 +1,42 @@
+// Keeps a game page up to date. The web_server pushes the game over a
+// WebSocket each time it changes (see src/live.rs), and the page is loaded
+// again when that differs from what it shows. If a WebSocket can't be
+// opened, eg through a proxy that doesn't allow them, server-sent events
+// are used instead. Without either the page's Refresh link still works.
+(function () {
+    var live = document.getElementById("live");
+    if (!live) {
+        return;
+    }
+
+    function update(data) {
+        var game = JSON.parse(data);
+        var cells = game.board.map(function (row) { return row.join(""); }).join("");
+        if (cells + game.status !== live.getAttribute("data-state")) {
+            location.assign(live.getAttribute("data-page"));
+        }
+    }
+
+    function listen() {
+        if (!window.EventSource) {
+            return;
+        }
+        var source = new EventSource(live.getAttribute("data-events"));
+        source.onmessage = function (event) { update(event.data); };
+    }
+
+    if (!window.WebSocket) {
+        listen();
+        return;
+    }
+    var scheme = location.protocol === "https:" ? "wss://" : "ws://";
+    var socket = new WebSocket(scheme + location.host + live.getAttribute("data-socket"));
+    var opened = false;
+    socket.onopen = function () { opened = true; };
+    socket.onmessage = function (event) { update(event.data); };
+    socket.onclose = function () {
+        if (!opened) {
+            listen();
+        }
+    };
+})();